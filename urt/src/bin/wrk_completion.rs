@@ -35,7 +35,7 @@ fn run_server() {
         }
 
         loop {
-            let stream = unordered.next().await.unwrap().unwrap();
+            let (stream, _) = unordered.next().await.unwrap().unwrap();
 
             unordered.push(listener.accept());
 
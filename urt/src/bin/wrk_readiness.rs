@@ -1,3 +1,4 @@
+use std::time::Duration;
 use tokio::io;
 use urt::net::{TcpListener, TcpStream};
 use urt::rt::Runtime;
@@ -11,13 +12,23 @@ fn main() {
         let listener = TcpListener::bind("[::1]:9000".parse().unwrap(), false).unwrap();
 
         loop {
-            let stream = listener.accept().await.unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
 
             urt::spawn(handle_connection(stream));
         }
     });
 
+    // stop serving on ctrl-c, then let in-flight ops wind down
+    let shutdown = runtime.shutdown_handle();
+
+    runtime.spawn(async move {
+        urt::signal::ctrl_c().await.unwrap();
+
+        shutdown.shutdown();
+    });
+
     runtime.run().unwrap();
+    runtime.shutdown(Duration::from_secs(5)).unwrap();
 }
 
 async fn handle_connection(mut stream: TcpStream) -> io::Result<()> {
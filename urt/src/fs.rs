@@ -0,0 +1,1212 @@
+use crate::io::cqe_result;
+use crate::{submit_op, sys};
+use futures::Stream;
+use io_uring::types::FsyncFlags;
+use std::alloc::{self, Layout};
+use std::collections::VecDeque;
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::ptr::NonNull;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fmt, io, mem, slice};
+
+/// A file opened through io_uring.
+///
+/// Reads and writes are positional and take ownership of their buffer for the duration of the
+/// op, handing it back alongside the result.
+#[derive(Debug)]
+pub struct File {
+    inner: std::fs::File,
+    direct: Option<DirectAlignment>,
+}
+
+/// Options for opening a [`File`], mirroring [`std::fs::OpenOptions`].
+#[derive(Debug, Clone)]
+pub struct OpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    truncate: bool,
+    create: bool,
+    create_new: bool,
+    mode: libc::mode_t,
+    custom_flags: libc::c_int,
+    direct: bool,
+}
+
+impl File {
+    /// Opens a file in read-only mode.
+    pub async fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        OpenOptions::new().read(true).open(path).await
+    }
+
+    /// Opens a file in write-only mode, creating it if needed and truncating it if it exists.
+    pub async fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .await
+    }
+
+    pub fn options() -> OpenOptions {
+        OpenOptions::new()
+    }
+
+    /// Reads into `buf` starting at `pos`, returning the number of bytes read and the buffer.
+    pub async fn read_at<T>(&self, buf: T, pos: u64) -> io::Result<(usize, T)>
+    where
+        T: AsMut<[u8]> + 'static,
+    {
+        // boxed so the buffer does not move between taking its address and the op completing
+        let mut buf = Box::new(buf);
+        let slice = (*buf).as_mut();
+
+        self.check_direct(slice.as_ptr(), slice.len(), pos)?;
+
+        let fd = io_uring::types::Fd(self.inner.as_raw_fd());
+
+        let entry = io_uring::opcode::Read::new(fd, slice.as_mut_ptr(), slice.len() as _)
+            .offset64(pos as _)
+            .build();
+
+        let (entry, buf) = unsafe { submit_op(entry, buf) }?.await;
+
+        let len = cqe_result(&entry)?;
+
+        Ok((len as usize, *buf))
+    }
+
+    /// Writes `buf` starting at `pos`, returning the number of bytes written and the buffer.
+    pub async fn write_at<T>(&self, buf: T, pos: u64) -> io::Result<(usize, T)>
+    where
+        T: AsRef<[u8]> + 'static,
+    {
+        let buf = Box::new(buf);
+        let slice = (*buf).as_ref();
+
+        self.check_direct(slice.as_ptr(), slice.len(), pos)?;
+
+        let fd = io_uring::types::Fd(self.inner.as_raw_fd());
+
+        let entry = io_uring::opcode::Write::new(fd, slice.as_ptr(), slice.len() as _)
+            .offset64(pos as _)
+            .build();
+
+        let (entry, buf) = unsafe { submit_op(entry, buf) }?.await;
+
+        let len = cqe_result(&entry)?;
+
+        Ok((len as usize, *buf))
+    }
+
+    /// The alignment `O_DIRECT` I/O on this file must follow, or `None` if it was not opened
+    /// with [`OpenOptions::direct`].
+    pub fn direct_alignment(&self) -> Option<DirectAlignment> {
+        self.direct
+    }
+
+    /// Rejects direct I/O the kernel would fail with `EINVAL`, without submitting it.
+    fn check_direct(&self, ptr: *const u8, len: usize, pos: u64) -> io::Result<()> {
+        let align = match self.direct {
+            Some(align) => align,
+            None => return Ok(()),
+        };
+
+        let msg = if !(ptr as usize).is_multiple_of(align.memory as usize) {
+            "buffer address is not aligned for direct I/O"
+        } else if !len.is_multiple_of(align.offset as usize) {
+            "buffer length is not aligned for direct I/O"
+        } else if !pos.is_multiple_of(align.offset as u64) {
+            "file offset is not aligned for direct I/O"
+        } else {
+            return Ok(());
+        };
+
+        Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+    }
+
+    /// Flushes data and metadata to disk.
+    pub async fn sync_all(&self) -> io::Result<()> {
+        self.fsync(FsyncFlags::empty()).await
+    }
+
+    /// Flushes data to disk, along with only the metadata needed to read it back.
+    pub async fn sync_data(&self) -> io::Result<()> {
+        self.fsync(FsyncFlags::DATASYNC).await
+    }
+
+    async fn fsync(&self, flags: FsyncFlags) -> io::Result<()> {
+        let fd = io_uring::types::Fd(self.inner.as_raw_fd());
+
+        let entry = io_uring::opcode::Fsync::new(fd).flags(flags).build();
+
+        let (entry, _) = unsafe { submit_op(entry, ()) }?.await;
+
+        cqe_result(&entry).map(|_| ())
+    }
+
+    /// Allocates disk space for `len` bytes starting at `offset`, extending the file if needed.
+    pub async fn allocate(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.fallocate(offset, len, 0).await
+    }
+
+    pub(crate) async fn fallocate(&self, offset: u64, len: u64, mode: i32) -> io::Result<()> {
+        let fd = io_uring::types::Fd(self.inner.as_raw_fd());
+
+        let entry = io_uring::opcode::Fallocate64::new(fd, len as _)
+            .offset64(offset as _)
+            .mode(mode)
+            .build();
+
+        let (entry, _) = unsafe { submit_op(entry, ()) }?.await;
+
+        cqe_result(&entry).map(|_| ())
+    }
+
+    /// Truncates or extends the file to `size` bytes.
+    ///
+    /// io_uring has no truncate op and `FALLOCATE` cannot shrink a file, so this runs
+    /// `ftruncate` on the blocking pool against a duplicate of the descriptor.
+    pub async fn set_len(&self, size: u64) -> io::Result<()> {
+        let file = self.inner.try_clone()?;
+
+        sys::run_blocking(move || file.set_len(size)).await
+    }
+
+    pub async fn metadata(&self) -> io::Result<Metadata> {
+        statx(
+            self.inner.as_raw_fd(),
+            CString::default(),
+            libc::AT_EMPTY_PATH,
+        )
+        .await
+    }
+
+    /// Closes the file, reporting any error from `close` which dropping it would discard.
+    pub async fn close(self) -> io::Result<()> {
+        let fd = self.inner.as_raw_fd();
+
+        let entry = io_uring::opcode::Close::new(io_uring::types::Fd(fd)).build();
+
+        // if the op can't be submitted, the fd is still ours and dropping `self` closes it
+        let op = unsafe { submit_op(entry, ()) }?;
+
+        // the op closes it from here on
+        let _ = self.inner.into_raw_fd();
+
+        let (entry, _) = op.await;
+
+        cqe_result(&entry).map(|_| ())
+    }
+}
+
+impl From<std::fs::File> for File {
+    /// Wraps a std file. Direct I/O alignment is not checked for files converted this way.
+    fn from(inner: std::fs::File) -> Self {
+        Self {
+            inner,
+            direct: None,
+        }
+    }
+}
+
+impl AsRawFd for File {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OpenOptions {
+    pub fn new() -> Self {
+        Self {
+            read: false,
+            write: false,
+            append: false,
+            truncate: false,
+            create: false,
+            create_new: false,
+            mode: 0o666,
+            custom_flags: 0,
+            direct: false,
+        }
+    }
+
+    pub fn read(&mut self, read: bool) -> &mut Self {
+        self.read = read;
+        self
+    }
+
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.write = write;
+        self
+    }
+
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.append = append;
+        self
+    }
+
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.truncate = truncate;
+        self
+    }
+
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    pub fn create_new(&mut self, create_new: bool) -> &mut Self {
+        self.create_new = create_new;
+        self
+    }
+
+    /// Sets the permission bits used if the file is created.
+    pub fn mode(&mut self, mode: u32) -> &mut Self {
+        self.mode = mode as libc::mode_t;
+        self
+    }
+
+    /// Adds extra `open(2)` flags, such as `O_NOATIME`. The access mode bits are ignored.
+    pub fn custom_flags(&mut self, flags: i32) -> &mut Self {
+        self.custom_flags = flags;
+        self
+    }
+
+    /// Opens the file with `O_DIRECT`, bypassing the page cache.
+    ///
+    /// Reads and writes on the file then need buffers, lengths and offsets aligned as reported by
+    /// [`File::direct_alignment`]; [`AlignedBuf`] provides suitable buffers.
+    pub fn direct(&mut self, direct: bool) -> &mut Self {
+        self.direct = direct;
+        self
+    }
+
+    pub async fn open<P: AsRef<Path>>(&self, path: P) -> io::Result<File> {
+        let direct = self.direct || self.custom_flags & libc::O_DIRECT != 0;
+
+        let flags = libc::O_CLOEXEC
+            | self.access_mode()?
+            | self.creation_mode()?
+            | (self.custom_flags & !libc::O_ACCMODE)
+            | if direct { libc::O_DIRECT } else { 0 };
+
+        let path = cstr(path.as_ref())?;
+
+        let entry =
+            io_uring::opcode::OpenAt::new(io_uring::types::Fd(libc::AT_FDCWD), path.as_ptr())
+                .flags(flags)
+                .mode(self.mode)
+                .build();
+
+        let (entry, _) = unsafe { submit_op(entry, path) }?.await;
+
+        let fd = cqe_result(&entry)?;
+
+        let mut file = File {
+            inner: unsafe { std::fs::File::from_raw_fd(fd as _) },
+            direct: None,
+        };
+
+        if direct {
+            let meta = file.metadata().await?;
+
+            // kernels before 6.1 don't report direct I/O alignment, where the block size is the
+            // conventional safe choice
+            file.direct = Some(meta.direct_alignment().unwrap_or(DirectAlignment {
+                memory: meta.inner.stx_blksize,
+                offset: meta.inner.stx_blksize,
+            }));
+        }
+
+        Ok(file)
+    }
+
+    fn access_mode(&self) -> io::Result<libc::c_int> {
+        match (self.read, self.write, self.append) {
+            (true, false, false) => Ok(libc::O_RDONLY),
+            (false, true, false) => Ok(libc::O_WRONLY),
+            (true, true, false) => Ok(libc::O_RDWR),
+            (false, _, true) => Ok(libc::O_WRONLY | libc::O_APPEND),
+            (true, _, true) => Ok(libc::O_RDWR | libc::O_APPEND),
+            (false, false, false) => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+
+    fn creation_mode(&self) -> io::Result<libc::c_int> {
+        match (self.write, self.append) {
+            (true, false) => {}
+            (false, false) => {
+                if self.truncate || self.create || self.create_new {
+                    return Err(io::Error::from_raw_os_error(libc::EINVAL));
+                }
+            }
+            (_, true) => {
+                if self.truncate && !self.create_new {
+                    return Err(io::Error::from_raw_os_error(libc::EINVAL));
+                }
+            }
+        }
+
+        Ok(match (self.create, self.truncate, self.create_new) {
+            (false, false, false) => 0,
+            (true, false, false) => libc::O_CREAT,
+            (false, true, false) => libc::O_TRUNC,
+            (true, true, false) => libc::O_CREAT | libc::O_TRUNC,
+            (_, _, true) => libc::O_CREAT | libc::O_EXCL,
+        })
+    }
+}
+
+/// Metadata about a file, as reported by `statx`.
+#[derive(Clone, Copy)]
+pub struct Metadata {
+    inner: libc::statx,
+}
+
+impl Metadata {
+    pub fn len(&self) -> u64 {
+        self.inner.stx_size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.file_type().is_file()
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type().is_dir()
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.file_type().is_symlink()
+    }
+
+    pub fn file_type(&self) -> FileType {
+        FileType {
+            mode: self.inner.stx_mode as libc::mode_t & libc::S_IFMT,
+        }
+    }
+
+    /// The permission bits of the file, without the file type.
+    pub fn mode(&self) -> u32 {
+        self.inner.stx_mode as u32 & !libc::S_IFMT
+    }
+
+    pub fn uid(&self) -> u32 {
+        self.inner.stx_uid
+    }
+
+    pub fn gid(&self) -> u32 {
+        self.inner.stx_gid
+    }
+
+    pub fn ino(&self) -> u64 {
+        self.inner.stx_ino
+    }
+
+    pub fn dev(&self) -> u64 {
+        libc::makedev(self.inner.stx_dev_major, self.inner.stx_dev_minor)
+    }
+
+    pub fn nlink(&self) -> u64 {
+        self.inner.stx_nlink as u64
+    }
+
+    pub fn blksize(&self) -> u64 {
+        self.inner.stx_blksize as u64
+    }
+
+    pub fn blocks(&self) -> u64 {
+        self.inner.stx_blocks
+    }
+
+    /// The alignment direct I/O on this file needs, if the kernel and filesystem report it.
+    pub fn direct_alignment(&self) -> Option<DirectAlignment> {
+        let inner = &self.inner;
+
+        if inner.stx_mask & libc::STATX_DIOALIGN == 0 || inner.stx_dio_offset_align == 0 {
+            return None;
+        }
+
+        Some(DirectAlignment {
+            memory: inner.stx_dio_mem_align,
+            offset: inner.stx_dio_offset_align,
+        })
+    }
+
+    pub fn accessed(&self) -> io::Result<SystemTime> {
+        self.timestamp(libc::STATX_ATIME, &self.inner.stx_atime)
+    }
+
+    pub fn modified(&self) -> io::Result<SystemTime> {
+        self.timestamp(libc::STATX_MTIME, &self.inner.stx_mtime)
+    }
+
+    /// The creation time, which not every filesystem records.
+    pub fn created(&self) -> io::Result<SystemTime> {
+        self.timestamp(libc::STATX_BTIME, &self.inner.stx_btime)
+    }
+
+    fn timestamp(&self, mask: u32, ts: &libc::statx_timestamp) -> io::Result<SystemTime> {
+        if self.inner.stx_mask & mask == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "timestamp not available on this filesystem",
+            ));
+        }
+
+        let secs = Duration::from_secs(ts.tv_sec.unsigned_abs());
+
+        // the nanoseconds always count forward, also from a second before the epoch
+        let time = if ts.tv_sec >= 0 {
+            UNIX_EPOCH + secs
+        } else {
+            UNIX_EPOCH - secs
+        };
+
+        Ok(time + Duration::from_nanos(ts.tv_nsec.into()))
+    }
+}
+
+impl fmt::Debug for Metadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metadata")
+            .field("len", &self.len())
+            .field("mode", &format_args!("{:o}", self.inner.stx_mode))
+            .field("uid", &self.uid())
+            .field("gid", &self.gid())
+            .field("ino", &self.ino())
+            .field("nlink", &self.nlink())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Alignment requirements for `O_DIRECT` I/O.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirectAlignment {
+    memory: u32,
+    offset: u32,
+}
+
+impl DirectAlignment {
+    /// The alignment of buffer addresses in memory.
+    pub fn memory(&self) -> usize {
+        self.memory as usize
+    }
+
+    /// The alignment of file offsets and of transfer lengths.
+    pub fn offset(&self) -> usize {
+        self.offset as usize
+    }
+}
+
+/// A zeroed, heap allocated byte buffer with a fixed alignment, for use with `O_DIRECT` files.
+///
+/// ```no_run
+/// # async fn f() -> std::io::Result<()> {
+/// use urt::fs::{AlignedBuf, OpenOptions};
+///
+/// let file = OpenOptions::new().read(true).direct(true).open("data").await?;
+/// let align = file.direct_alignment().unwrap();
+///
+/// let buf = AlignedBuf::new(align.offset() * 8, align.memory());
+/// let (n, buf) = file.read_at(buf, 0).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct AlignedBuf {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+impl AlignedBuf {
+    /// Allocates `len` zeroed bytes aligned to `align`.
+    ///
+    /// # Panics
+    /// If `align` is not a power of two, or `len` rounded up to `align` overflows `isize`.
+    pub fn new(len: usize, align: usize) -> Self {
+        let layout = Layout::from_size_align(len, align).expect("Invalid buffer alignment");
+
+        let ptr = if len == 0 {
+            // a well aligned dangling pointer, as zero sized allocations are not allowed
+            NonNull::new(align as *mut u8).unwrap()
+        } else {
+            let ptr = unsafe { alloc::alloc_zeroed(layout) };
+
+            NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout))
+        };
+
+        Self { ptr, layout }
+    }
+
+    pub fn len(&self) -> usize {
+        self.layout.size()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn align(&self) -> usize {
+        self.layout.align()
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len()) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len()) }
+    }
+}
+
+impl AsRef<[u8]> for AlignedBuf {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl AsMut<[u8]> for AlignedBuf {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        if self.layout.size() != 0 {
+            unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) };
+        }
+    }
+}
+
+impl fmt::Debug for AlignedBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AlignedBuf")
+            .field("len", &self.len())
+            .field("align", &self.align())
+            .finish()
+    }
+}
+
+// the buffer is uniquely owned, like a `Box<[u8]>`
+unsafe impl Send for AlignedBuf {}
+unsafe impl Sync for AlignedBuf {}
+
+/// The type of a file, as found in [`Metadata`] or a [`DirEntry`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileType {
+    mode: libc::mode_t,
+}
+
+impl FileType {
+    pub fn is_file(&self) -> bool {
+        self.mode == libc::S_IFREG
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode == libc::S_IFDIR
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode == libc::S_IFLNK
+    }
+}
+
+/// Size of the buffer handed to each `getdents64` call, in 8 byte words so records stay aligned.
+const DIRENT_BUF_WORDS: usize = 4096;
+
+/// Returns a stream over the entries of the directory at `path`, excluding `.` and `..`.
+///
+/// io_uring has no getdents op, so batches are read on the runtime's blocking pool and the task
+/// is woken through the driver's eventfd once each batch is ready.
+pub async fn read_dir<P: AsRef<Path>>(path: P) -> io::Result<ReadDir> {
+    let path = path.as_ref();
+
+    let dir = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECTORY)
+        .open(path)
+        .await?;
+
+    Ok(ReadDir {
+        root: Arc::new(path.to_path_buf()),
+        entries: VecDeque::new(),
+        state: ReadDirState::Idle(dir.inner),
+    })
+}
+
+/// A stream of [`DirEntry`]s, returned by [`read_dir`].
+pub struct ReadDir {
+    root: Arc<PathBuf>,
+    entries: VecDeque<RawDirEntry>,
+    state: ReadDirState,
+}
+
+enum ReadDirState {
+    Idle(std::fs::File),
+    Reading(sys::Blocking<(std::fs::File, io::Result<Vec<RawDirEntry>>)>),
+    Done,
+}
+
+struct RawDirEntry {
+    name: OsString,
+    ino: u64,
+    d_type: u8,
+}
+
+impl Stream for ReadDir {
+    type Item = io::Result<DirEntry>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        loop {
+            if let Some(raw) = this.entries.pop_front() {
+                return Poll::Ready(Some(Ok(DirEntry {
+                    root: this.root.clone(),
+                    name: raw.name,
+                    ino: raw.ino,
+                    d_type: raw.d_type,
+                })));
+            }
+
+            match mem::replace(&mut this.state, ReadDirState::Done) {
+                ReadDirState::Idle(dir) => {
+                    this.state = ReadDirState::Reading(sys::run_blocking(move || {
+                        let batch = getdents(&dir);
+
+                        (dir, batch)
+                    }));
+                }
+                ReadDirState::Reading(mut batch) => match Pin::new(&mut batch).poll(cx) {
+                    Poll::Pending => {
+                        this.state = ReadDirState::Reading(batch);
+
+                        return Poll::Pending;
+                    }
+                    // an empty batch means the end of the directory, and dropping `dir` closes it
+                    Poll::Ready((_, Ok(entries))) if entries.is_empty() => {}
+                    Poll::Ready((dir, Ok(entries))) => {
+                        this.entries.extend(entries);
+                        this.state = ReadDirState::Idle(dir);
+                    }
+                    Poll::Ready((_, Err(err))) => return Poll::Ready(Some(Err(err))),
+                },
+                ReadDirState::Done => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ReadDir {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ReadDir").field(&self.root).finish()
+    }
+}
+
+/// Reads the next batch of entries from `dir`, returning an empty batch at the end.
+fn getdents(dir: &std::fs::File) -> io::Result<Vec<RawDirEntry>> {
+    let mut buf = vec![0u64; DIRENT_BUF_WORDS];
+
+    loop {
+        let read = unsafe {
+            libc::syscall(
+                libc::SYS_getdents64,
+                dir.as_raw_fd(),
+                buf.as_mut_ptr(),
+                buf.len() * mem::size_of::<u64>(),
+            )
+        };
+
+        if read == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if read == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut entries = Vec::new();
+        let mut offset = 0;
+
+        while offset < read as usize {
+            // the kernel pads every record to 8 bytes, so each one starts aligned
+            let dirent =
+                unsafe { &*((buf.as_ptr() as *const u8).add(offset) as *const libc::dirent64) };
+
+            let name = unsafe { CStr::from_ptr(dirent.d_name.as_ptr()) }.to_bytes();
+
+            if name != b"." && name != b".." {
+                entries.push(RawDirEntry {
+                    name: OsStr::from_bytes(name).to_os_string(),
+                    ino: dirent.d_ino,
+                    d_type: dirent.d_type,
+                });
+            }
+
+            offset += dirent.d_reclen as usize;
+        }
+
+        // a batch holding only `.` and `..` is not the end, so keep reading
+        if !entries.is_empty() {
+            return Ok(entries);
+        }
+    }
+}
+
+/// An entry yielded by [`ReadDir`].
+pub struct DirEntry {
+    root: Arc<PathBuf>,
+    name: OsString,
+    ino: u64,
+    d_type: u8,
+}
+
+impl DirEntry {
+    pub fn path(&self) -> PathBuf {
+        self.root.join(&self.name)
+    }
+
+    pub fn file_name(&self) -> OsString {
+        self.name.clone()
+    }
+
+    pub fn ino(&self) -> u64 {
+        self.ino
+    }
+
+    /// Returns the entry's type, falling back to a `statx` if the filesystem does not report it
+    /// in the directory listing.
+    pub async fn file_type(&self) -> io::Result<FileType> {
+        if self.d_type == libc::DT_UNKNOWN {
+            return Ok(self.metadata().await?.file_type());
+        }
+
+        Ok(FileType {
+            mode: (self.d_type as libc::mode_t) << 12,
+        })
+    }
+
+    /// Returns metadata for the entry itself, without following a final symlink.
+    pub async fn metadata(&self) -> io::Result<Metadata> {
+        symlink_metadata(self.path()).await
+    }
+}
+
+impl fmt::Debug for DirEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("DirEntry").field(&self.path()).finish()
+    }
+}
+
+/// Returns metadata for `path`, following symlinks.
+pub async fn metadata<P: AsRef<Path>>(path: P) -> io::Result<Metadata> {
+    statx(libc::AT_FDCWD, cstr(path.as_ref())?, 0).await
+}
+
+/// Returns metadata for `path` itself, without following a final symlink.
+pub async fn symlink_metadata<P: AsRef<Path>>(path: P) -> io::Result<Metadata> {
+    statx(
+        libc::AT_FDCWD,
+        cstr(path.as_ref())?,
+        libc::AT_SYMLINK_NOFOLLOW,
+    )
+    .await
+}
+
+/// Renames `from` to `to`, replacing `to` if it already exists.
+pub async fn rename<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<()> {
+    let paths = (cstr(from.as_ref())?, cstr(to.as_ref())?);
+
+    let entry = io_uring::opcode::RenameAt::new(
+        io_uring::types::Fd(libc::AT_FDCWD),
+        paths.0.as_ptr(),
+        io_uring::types::Fd(libc::AT_FDCWD),
+        paths.1.as_ptr(),
+    )
+    .build();
+
+    path_op(entry, paths).await
+}
+
+pub async fn remove_file<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    unlink(path.as_ref(), 0).await
+}
+
+/// Removes an empty directory.
+pub async fn remove_dir<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    unlink(path.as_ref(), libc::AT_REMOVEDIR).await
+}
+
+async fn unlink(path: &Path, flags: i32) -> io::Result<()> {
+    let path = cstr(path)?;
+
+    let entry = io_uring::opcode::UnlinkAt::new(io_uring::types::Fd(libc::AT_FDCWD), path.as_ptr())
+        .flags(flags)
+        .build();
+
+    path_op(entry, path).await
+}
+
+pub async fn create_dir<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let path = cstr(path.as_ref())?;
+
+    let entry = io_uring::opcode::MkDirAt::new(io_uring::types::Fd(libc::AT_FDCWD), path.as_ptr())
+        .mode(0o777)
+        .build();
+
+    path_op(entry, path).await
+}
+
+/// Creates `link` as a new hard link to `original`.
+pub async fn hard_link<P: AsRef<Path>, Q: AsRef<Path>>(original: P, link: Q) -> io::Result<()> {
+    let paths = (cstr(original.as_ref())?, cstr(link.as_ref())?);
+
+    let entry = io_uring::opcode::LinkAt::new(
+        io_uring::types::Fd(libc::AT_FDCWD),
+        paths.0.as_ptr(),
+        io_uring::types::Fd(libc::AT_FDCWD),
+        paths.1.as_ptr(),
+    )
+    .build();
+
+    path_op(entry, paths).await
+}
+
+/// Creates `link` as a symlink pointing at `original`.
+pub async fn symlink<P: AsRef<Path>, Q: AsRef<Path>>(original: P, link: Q) -> io::Result<()> {
+    let paths = (cstr(original.as_ref())?, cstr(link.as_ref())?);
+
+    let entry = io_uring::opcode::SymlinkAt::new(
+        io_uring::types::Fd(libc::AT_FDCWD),
+        paths.0.as_ptr(),
+        paths.1.as_ptr(),
+    )
+    .build();
+
+    path_op(entry, paths).await
+}
+
+async fn statx(dirfd: RawFd, path: CString, flags: i32) -> io::Result<Metadata> {
+    // the path and the buffer the kernel fills in both have to outlive the op
+    let mut data = Box::new((path, unsafe { mem::zeroed::<libc::statx>() }));
+
+    let entry = io_uring::opcode::Statx::new(
+        io_uring::types::Fd(dirfd),
+        data.0.as_ptr(),
+        &mut data.1 as *mut libc::statx as *mut io_uring::types::statx,
+    )
+    .flags(flags)
+    .mask(libc::STATX_BASIC_STATS | libc::STATX_BTIME | libc::STATX_DIOALIGN)
+    .build();
+
+    let (entry, data) = unsafe { submit_op(entry, data) }?.await;
+
+    cqe_result(&entry)?;
+
+    Ok(Metadata { inner: data.1 })
+}
+
+/// Submits an op that only references the paths held in `paths` and returns no value.
+async fn path_op<T: Unpin + 'static>(entry: io_uring::squeue::Entry, paths: T) -> io::Result<()> {
+    let (entry, _) = unsafe { submit_op(entry, paths) }?.await;
+
+    cqe_result(&entry).map(|_| ())
+}
+
+/// Converts `path` into the NUL-terminated form the kernel expects.
+pub(crate) fn cstr(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "path contained an interior nul byte",
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rt::Runtime;
+    use futures::StreamExt;
+
+    #[test]
+    fn test_file_io() {
+        let path = std::env::temp_dir().join(format!("urt-file-io-{}", std::process::id()));
+
+        let mut runtime = Runtime::new(256).unwrap();
+
+        let task_path = path.clone();
+
+        runtime.spawn(async move {
+            let file = File::create(&task_path).await.unwrap();
+
+            let (len, _) = file.write_at(b"hello world", 0).await.unwrap();
+            assert_eq!(11, len);
+
+            let (len, _) = file.write_at(b"urt", 6).await.unwrap();
+            assert_eq!(3, len);
+
+            file.sync_all().await.unwrap();
+            file.close().await.unwrap();
+
+            let file = File::open(&task_path).await.unwrap();
+
+            let (len, buf) = file.read_at(vec![0; 64], 0).await.unwrap();
+            assert_eq!(b"hello urtld", &buf[..len]);
+
+            let (len, buf) = file.read_at(vec![0; 64], 6).await.unwrap();
+            assert_eq!(b"urtld", &buf[..len]);
+
+            // reads past the end come back empty
+            let (len, _) = file.read_at(vec![0; 64], 100).await.unwrap();
+            assert_eq!(0, len);
+
+            let err = file.write_at(b"nope", 0).await.unwrap_err();
+            assert_eq!(Some(libc::EBADF), err.raw_os_error());
+
+            let file = File::options().write(true).open(&task_path).await.unwrap();
+
+            file.allocate(0, 4096).await.unwrap();
+            assert_eq!(4096, std::fs::metadata(&task_path).unwrap().len());
+
+            file.set_len(5).await.unwrap();
+            file.sync_data().await.unwrap();
+            assert_eq!(b"hello", &std::fs::read(&task_path).unwrap()[..]);
+
+            let err = File::options()
+                .write(true)
+                .create_new(true)
+                .open(&task_path)
+                .await
+                .unwrap_err();
+            assert_eq!(io::ErrorKind::AlreadyExists, err.kind());
+        });
+
+        runtime.run().unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_path_ops() {
+        let dir = std::env::temp_dir().join(format!("urt-path-ops-{}", std::process::id()));
+
+        let mut runtime = Runtime::new(256).unwrap();
+
+        let task_dir = dir.clone();
+
+        runtime.spawn(async move {
+            let dir = task_dir;
+
+            create_dir(&dir).await.unwrap();
+            assert!(metadata(&dir).await.unwrap().is_dir());
+
+            let err = create_dir(&dir).await.unwrap_err();
+            assert_eq!(io::ErrorKind::AlreadyExists, err.kind());
+
+            let a = dir.join("a");
+            let b = dir.join("b");
+
+            let file = File::create(&a).await.unwrap();
+            file.write_at(b"hello", 0).await.unwrap();
+
+            let meta = file.metadata().await.unwrap();
+            assert!(meta.is_file());
+            assert_eq!(5, meta.len());
+            assert!(meta.modified().is_ok());
+
+            // 1000.5 seconds before the epoch is -1001 seconds plus half a second
+            let times = [libc::timespec {
+                tv_sec: -1001,
+                tv_nsec: 500_000_000,
+            }; 2];
+
+            assert_eq!(0, unsafe {
+                libc::futimens(file.as_raw_fd(), times.as_ptr())
+            });
+
+            assert_eq!(
+                UNIX_EPOCH - Duration::from_millis(1_000_500),
+                file.metadata().await.unwrap().modified().unwrap()
+            );
+
+            file.close().await.unwrap();
+
+            rename(&a, &b).await.unwrap();
+
+            let err = metadata(&a).await.unwrap_err();
+            assert_eq!(io::ErrorKind::NotFound, err.kind());
+
+            hard_link(&b, &a).await.unwrap();
+            assert_eq!(2, metadata(&b).await.unwrap().nlink());
+
+            let link = dir.join("link");
+
+            symlink(&b, &link).await.unwrap();
+            assert!(symlink_metadata(&link).await.unwrap().is_symlink());
+            assert_eq!(5, metadata(&link).await.unwrap().len());
+
+            // non-empty directories can't be removed
+            let err = remove_dir(&dir).await.unwrap_err();
+            assert_eq!(Some(libc::ENOTEMPTY), err.raw_os_error());
+
+            remove_file(&link).await.unwrap();
+            remove_file(&a).await.unwrap();
+            remove_file(&b).await.unwrap();
+            remove_dir(&dir).await.unwrap();
+        });
+
+        runtime.run().unwrap();
+
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_read_dir() {
+        let dir = std::env::temp_dir().join(format!("urt-read-dir-{}", std::process::id()));
+
+        let mut runtime = Runtime::new(256).unwrap();
+
+        let task_dir = dir.clone();
+
+        runtime.spawn(async move {
+            let dir = task_dir;
+
+            create_dir(&dir).await.unwrap();
+            create_dir(dir.join("sub")).await.unwrap();
+
+            // enough entries to need more than one getdents64 batch
+            for i in 0..2000 {
+                File::create(dir.join(format!("file-{i}")))
+                    .await
+                    .unwrap()
+                    .close()
+                    .await
+                    .unwrap();
+            }
+
+            symlink("sub", dir.join("link")).await.unwrap();
+
+            let mut entries = read_dir(&dir).await.unwrap();
+
+            let mut files = 0;
+
+            while let Some(entry) = entries.next().await {
+                let entry = entry.unwrap();
+                let file_type = entry.file_type().await.unwrap();
+
+                assert_eq!(dir.join(entry.file_name()), entry.path());
+
+                match entry.file_name().to_str().unwrap() {
+                    "sub" => assert!(file_type.is_dir()),
+                    "link" => assert!(file_type.is_symlink()),
+                    name => {
+                        assert!(name.starts_with("file-"));
+                        assert!(file_type.is_file());
+
+                        files += 1;
+                    }
+                }
+            }
+
+            assert_eq!(2000, files);
+
+            let err = read_dir(dir.join("file-0")).await.unwrap_err();
+            assert_eq!(Some(libc::ENOTDIR), err.raw_os_error());
+        });
+
+        runtime.run().unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_direct_io() {
+        let buf = AlignedBuf::new(1000, 512);
+        assert_eq!(0, buf.as_ptr() as usize % 512);
+        assert!(buf.iter().all(|&b| b == 0));
+
+        let path = std::env::temp_dir().join(format!("urt-direct-io-{}", std::process::id()));
+
+        let mut runtime = Runtime::new(256).unwrap();
+
+        let task_path = path.clone();
+
+        runtime.spawn(async move {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .direct(true)
+                .open(&task_path)
+                .await;
+
+            let file = match file {
+                Ok(file) => file,
+                // not every filesystem supports O_DIRECT
+                Err(err) if err.raw_os_error() == Some(libc::EINVAL) => return,
+                Err(err) => panic!("{err}"),
+            };
+
+            let align = file.direct_alignment().unwrap();
+            let len = align.offset() * 4;
+
+            let mut buf = AlignedBuf::new(len, align.memory());
+            buf.fill(7);
+
+            let (n, _) = file.write_at(buf, align.offset() as u64).await.unwrap();
+            assert_eq!(len, n);
+
+            let (n, buf) = file
+                .read_at(AlignedBuf::new(len, align.memory()), align.offset() as u64)
+                .await
+                .unwrap();
+            assert_eq!(len, n);
+            assert!(buf.iter().all(|&b| b == 7));
+
+            // misaligned requests are refused before reaching the kernel
+            let buf = AlignedBuf::new(len, align.memory());
+            let err = file.read_at(buf, 1).await.unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+            let buf = AlignedBuf::new(len + 1, align.memory());
+            let err = file.write_at(buf, 0).await.unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+            file.close().await.unwrap();
+
+            remove_file(&task_path).await.unwrap();
+        });
+
+        runtime.run().unwrap();
+
+        assert!(!path.exists());
+    }
+}
@@ -1,18 +1,50 @@
+use crate::net::TcpStream;
+use crate::pipe::{self, Receiver, Sender};
 use crate::submit_op;
-use crate::sys::{Op, CONTEXT};
+use crate::sys::{self, coop, Op};
+use futures::future;
+use futures::io::{AsyncRead, AsyncWrite};
 use futures::{pin_mut, ready};
 use io_uring::squeue::Flags;
 use io_uring::{cqueue, squeue};
+use std::ffi::CString;
 use std::future::Future;
-use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
 use std::pin::Pin;
+use std::rc::Rc;
 use std::task::{Context, Poll};
+use std::{fmt, io, mem};
 
-pub fn prepare_batch(size: usize) -> io::Result<()> {
-    CONTEXT.with(|x| {
-        let outer_guard = x.borrow();
-        let cx = outer_guard.as_ref().unwrap();
+/// Size of the buffer [`Stdin`], [`Stdout`], [`Stderr`] and pipes move data through.
+const FD_BUF_SIZE: usize = 8 * 1024;
+
+/// How much a single splice moves through an intermediate pipe, the default pipe capacity.
+pub(crate) const PIPE_CHUNK: u32 = 64 * 1024;
+
+/// Converts the result of a completion into an `io::Result`, mapping negative values to the errno
+/// they encode.
+#[inline]
+pub(crate) fn cqe_result(entry: &cqueue::Entry) -> io::Result<u32> {
+    let res = entry.result();
+
+    if res >= 0 {
+        Ok(res as u32)
+    } else {
+        Err(io::Error::from_raw_os_error(-res))
+    }
+}
+
+/// Waits until `fd` reports any of `events`.
+pub(crate) async fn poll_ready(fd: RawFd, events: libc::c_short) -> io::Result<()> {
+    let entry = io_uring::opcode::PollAdd::new(io_uring::types::Fd(fd), events as _).build();
 
+    unsafe { submit_op(entry, ())?.await };
+
+    Ok(())
+}
+
+pub fn prepare_batch(size: usize) -> io::Result<()> {
+    sys::with_context(|cx| {
         let mut driver = cx.driver.borrow_mut();
 
         let len = driver.get_remaining();
@@ -68,6 +100,8 @@ where
     F: FnOnce(cqueue::Entry, D) -> io::Result<O>,
     D: Unpin + 'static,
 {
+    /// # Safety
+    /// Any memory referenced by `entry` must be owned by `data`.
     pub unsafe fn from_raw(entry: squeue::Entry, data: D, post_op: F) -> Self {
         Self {
             entry,
@@ -76,6 +110,9 @@ where
         }
     }
 
+    /// # Safety
+    /// Linking flags change which ops the kernel will run; the caller must submit the linked ops
+    /// back-to-back.
     pub unsafe fn apply_flags(&mut self, flags: Flags) {
         self.entry = self.entry.clone().flags(flags);
     }
@@ -89,3 +126,675 @@ where
         })
     }
 }
+
+/// Moves up to `len` bytes from `from` to `to` inside the kernel, returning how many were moved.
+///
+/// At least one of the two must be a pipe. A return of 0 means `from` reached end of input.
+pub async fn splice<F: AsRawFd, T: AsRawFd>(from: &F, to: &T, len: u32) -> io::Result<usize> {
+    unsafe { splice_op(from.as_raw_fd(), -1, to.as_raw_fd(), -1, len, 0, ()) }
+        .submit()?
+        .await
+}
+
+/// Copies up to `len` bytes from one pipe into another without consuming them from `from`.
+pub async fn tee(from: &Receiver, to: &Sender, len: u32) -> io::Result<usize> {
+    let entry = io_uring::opcode::Tee::new(
+        io_uring::types::Fd(from.as_raw_fd()),
+        io_uring::types::Fd(to.as_raw_fd()),
+        len,
+    )
+    .build();
+
+    let (entry, _) = unsafe { submit_op(entry, ()) }?.await;
+
+    cqe_result(&entry).map(|len| len as usize)
+}
+
+/// Builds a splice between two descriptors. An offset of -1 uses (and advances) the descriptor's
+/// own position, and must be used for pipes and sockets.
+///
+/// # Safety
+/// Both descriptors must stay open until the op completes, for example by being owned by `data`.
+#[allow(clippy::type_complexity, clippy::too_many_arguments)]
+pub(crate) unsafe fn splice_op<D: Unpin + 'static>(
+    fd_in: RawFd,
+    off_in: i64,
+    fd_out: RawFd,
+    off_out: i64,
+    len: u32,
+    flags: u32,
+    data: D,
+) -> Unsubmitted<D, usize, impl FnOnce(cqueue::Entry, D) -> io::Result<usize>> {
+    let entry = io_uring::opcode::Splice::new(
+        io_uring::types::Fd(fd_in),
+        off_in,
+        io_uring::types::Fd(fd_out),
+        off_out,
+        len,
+    )
+    .flags(flags)
+    .build();
+
+    let post_op = |entry: cqueue::Entry, _| cqe_result(&entry).map(|len| len as usize);
+
+    Unsubmitted::from_raw(entry, data, post_op)
+}
+
+/// Copies data in both directions between `a` and `b` until each reaches end of input, returning
+/// the bytes copied from `a` to `b` and from `b` to `a`.
+///
+/// Each direction goes through its own pipe: a splice from the source into the pipe is
+/// hard-linked to a splice from the pipe into the destination, so every round trip is a single
+/// submission and the data never reaches userspace. Once a side reaches end of input, the write
+/// half of the other side is shut down.
+pub async fn copy_bidirectional(a: &TcpStream, b: &TcpStream) -> io::Result<(u64, u64)> {
+    future::try_join(
+        copy_one_way(a.as_raw_fd(), b.as_raw_fd()),
+        copy_one_way(b.as_raw_fd(), a.as_raw_fd()),
+    )
+    .await
+}
+
+async fn copy_one_way(from: RawFd, to: RawFd) -> io::Result<u64> {
+    // shared with every op so the pipe outlives them even if this future is dropped
+    let pipe = Rc::new(pipe::pipe()?);
+    let (pipe_tx, pipe_rx) = (pipe.0.as_raw_fd(), pipe.1.as_raw_fd());
+
+    let mut copied = 0;
+    let mut in_pipe = 0;
+
+    loop {
+        // the destination took less than the previous round moved into the pipe
+        while in_pipe > 0 {
+            let drain = unsafe { splice_op(pipe_rx, -1, to, -1, in_pipe, 0, pipe.clone()) };
+
+            match drain.submit()?.await {
+                Ok(len) => {
+                    in_pipe -= len as u32;
+                    copied += len as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    poll_ready(to, libc::POLLOUT).await?
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        prepare_batch(2)?;
+
+        let mut fill = unsafe { splice_op(from, -1, pipe_tx, -1, PIPE_CHUNK, 0, pipe.clone()) };
+
+        // a short splice counts as a failure for links, so only a hard link still runs the drain
+        unsafe { fill.apply_flags(Flags::IO_HARDLINK) };
+
+        // non-blocking on the pipe, so an empty fill doesn't leave the drain waiting forever
+        let drain = unsafe {
+            splice_op(
+                pipe_rx,
+                -1,
+                to,
+                -1,
+                PIPE_CHUNK,
+                libc::SPLICE_F_NONBLOCK,
+                pipe.clone(),
+            )
+        };
+
+        let (filled, drained) = future::join(fill.submit()?, drain.submit()?).await;
+
+        let drained = match drained {
+            Ok(len) => len as u32,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => 0,
+            Err(e) => return Err(e),
+        };
+
+        copied += drained as u64;
+
+        match filled {
+            Ok(0) => break,
+            Ok(len) => in_pipe = len as u32 - drained,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                poll_ready(from, libc::POLLIN).await?
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    // the peer may already be gone, in which case there is nobody to tell
+    if unsafe { libc::shutdown(to, libc::SHUT_WR) } == -1 {
+        let err = io::Error::last_os_error();
+
+        if err.raw_os_error() != Some(libc::ENOTCONN) {
+            return Err(err);
+        }
+    }
+
+    Ok(copied)
+}
+
+/// A handle to the standard input of the current process.
+#[derive(Debug)]
+pub struct Stdin {
+    io: FdIo,
+}
+
+/// A handle to the standard output of the current process.
+///
+/// Writes are not buffered beyond the single write in flight, and separate handles may
+/// interleave.
+#[derive(Debug)]
+pub struct Stdout {
+    io: FdIo,
+}
+
+/// A handle to the standard error of the current process.
+#[derive(Debug)]
+pub struct Stderr {
+    io: FdIo,
+}
+
+pub fn stdin() -> Stdin {
+    Stdin {
+        io: FdIo::new(libc::STDIN_FILENO),
+    }
+}
+
+pub fn stdout() -> Stdout {
+    Stdout {
+        io: FdIo::new(libc::STDOUT_FILENO),
+    }
+}
+
+pub fn stderr() -> Stderr {
+    Stderr {
+        io: FdIo::new(libc::STDERR_FILENO),
+    }
+}
+
+impl AsyncRead for Stdin {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.io.poll_read(cx, buf)
+    }
+}
+
+macro_rules! impl_async_write {
+    ($($ty:ty),*) => {$(
+        impl AsyncWrite for $ty {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                self.io.poll_write(cx, buf)
+            }
+
+            fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                self.io.poll_flush(cx)
+            }
+
+            /// Flushes, leaving the process's stream open.
+            fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                self.io.poll_flush(cx)
+            }
+        }
+    )*};
+}
+
+impl_async_write!(Stdout, Stderr);
+
+/// Async reads and writes on a descriptor that is not a socket, such as a pipe or a standard
+/// stream, for the [`AsyncRead`] and [`AsyncWrite`] impls.
+///
+/// Data moves through an owned buffer with `READ`/`WRITE` ops at offset -1, so the fd's own
+/// position is used and non-seekable fds work. Terminals fall back to waiting for readiness and
+/// calling `read`/`write` directly, since a read op on a tty would park an io-wq thread until the
+/// user types something, and can't be cancelled. Those calls go through a non-blocking description
+/// of the terminal opened just for this, so a write larger than the room POLLOUT reported comes
+/// back short instead of blocking the ring thread, while the flags of the description the process
+/// shares with its parent stay as they are.
+pub(crate) struct FdIo {
+    fd: RawFd,
+    readiness: bool,
+    nonblocking: Option<OwnedFd>,
+    read: ReadState,
+    write: WriteState,
+}
+
+enum ReadState {
+    Idle(Vec<u8>),
+    Reading(Op<Vec<u8>>),
+    Polling(Op<()>),
+    /// Data read ahead of the caller, as the buffer and the unread range in it.
+    Buffered(Vec<u8>, usize, usize),
+}
+
+enum WriteState {
+    Idle(Vec<u8>),
+    Writing(Op<Vec<u8>>),
+    Polling(Op<()>),
+    Closing(Op<()>),
+}
+
+impl FdIo {
+    pub(crate) fn new(fd: RawFd) -> Self {
+        let readiness = unsafe { libc::isatty(fd) } == 1;
+
+        // without it, reads and writes still work but may block after a wakeup
+        let nonblocking = if readiness {
+            reopen_nonblocking(fd)
+        } else {
+            None
+        };
+
+        Self {
+            fd,
+            readiness,
+            nonblocking,
+            read: ReadState::Idle(Vec::new()),
+            write: WriteState::Idle(Vec::new()),
+        }
+    }
+
+    pub(crate) fn poll_read(
+        &mut self,
+        cx: &mut Context<'_>,
+        out: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            match &mut self.read {
+                ReadState::Idle(_) if self.readiness => {
+                    self.read = ReadState::Polling(poll_op(self.readiness_fd(), libc::POLLIN)?);
+                }
+                ReadState::Idle(buf) => {
+                    let mut buf = mem::take(buf);
+                    buf.resize(FD_BUF_SIZE, 0);
+
+                    let entry = io_uring::opcode::Read::new(
+                        io_uring::types::Fd(self.fd),
+                        buf.as_mut_ptr(),
+                        buf.len() as _,
+                    )
+                    .offset64(-1)
+                    .build();
+
+                    self.read = ReadState::Reading(unsafe { submit_op(entry, buf) }?);
+                }
+                ReadState::Reading(op) => {
+                    let (entry, buf) = ready!(Pin::new(op).poll(cx));
+
+                    match cqe_result(&entry) {
+                        Ok(0) => {
+                            self.read = ReadState::Idle(buf);
+
+                            return Poll::Ready(Ok(0));
+                        }
+                        Ok(len) => self.read = ReadState::Buffered(buf, 0, len as usize),
+                        Err(e) => {
+                            self.read = ReadState::Idle(buf);
+
+                            return Poll::Ready(Err(e));
+                        }
+                    }
+                }
+                ReadState::Polling(op) => {
+                    ready!(Pin::new(op).poll(cx));
+
+                    self.read = ReadState::Idle(Vec::new());
+
+                    let res = unsafe {
+                        libc::read(self.readiness_fd(), out.as_mut_ptr() as _, out.len())
+                    };
+
+                    match syscall_result(res) {
+                        // someone else got to the data first
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                        res => return Poll::Ready(res),
+                    }
+                }
+                ReadState::Buffered(buf, start, end) => {
+                    ready!(coop::poll_budget(cx));
+                    coop::consume();
+
+                    let len = out.len().min(*end - *start);
+
+                    out[..len].copy_from_slice(&buf[*start..*start + len]);
+                    *start += len;
+
+                    if start == end {
+                        self.read = ReadState::Idle(mem::take(buf));
+                    }
+
+                    return Poll::Ready(Ok(len));
+                }
+            }
+        }
+    }
+
+    /// Writes at most one buffer's worth of `data`.
+    ///
+    /// The data is copied into the owned buffer when the write is submitted; if this returns
+    /// `Pending`, the next call reports the result of that write whatever it is passed, as
+    /// callers retry with the same data.
+    pub(crate) fn poll_write(
+        &mut self,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            match &mut self.write {
+                WriteState::Idle(_) if self.readiness => {
+                    self.write = WriteState::Polling(poll_op(self.readiness_fd(), libc::POLLOUT)?);
+                }
+                WriteState::Idle(buf) => {
+                    let mut buf = mem::take(buf);
+
+                    buf.clear();
+                    buf.extend_from_slice(&data[..data.len().min(FD_BUF_SIZE)]);
+
+                    let entry = io_uring::opcode::Write::new(
+                        io_uring::types::Fd(self.fd),
+                        buf.as_ptr(),
+                        buf.len() as _,
+                    )
+                    .offset64(-1)
+                    .build();
+
+                    self.write = WriteState::Writing(unsafe { submit_op(entry, buf) }?);
+                }
+                WriteState::Writing(op) => {
+                    let (entry, buf) = ready!(Pin::new(op).poll(cx));
+
+                    self.write = WriteState::Idle(buf);
+
+                    return Poll::Ready(cqe_result(&entry).map(|len| len as usize));
+                }
+                WriteState::Polling(op) => {
+                    ready!(Pin::new(op).poll(cx));
+
+                    self.write = WriteState::Idle(Vec::new());
+
+                    let res =
+                        unsafe { libc::write(self.readiness_fd(), data.as_ptr() as _, data.len()) };
+
+                    match syscall_result(res) {
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                        res => return Poll::Ready(res),
+                    }
+                }
+                WriteState::Closing(_) => {
+                    return Poll::Ready(Err(io::Error::from_raw_os_error(libc::EBADF)));
+                }
+            }
+        }
+    }
+
+    /// The descriptor to wait for readiness on and then read or write directly.
+    fn readiness_fd(&self) -> RawFd {
+        self.nonblocking
+            .as_ref()
+            .map_or(self.fd, AsRawFd::as_raw_fd)
+    }
+
+    /// Waits for a write still in flight, discarding its result since nobody is waiting for it.
+    pub(crate) fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.write {
+            WriteState::Writing(op) => {
+                let (entry, buf) = ready!(Pin::new(op).poll(cx));
+
+                self.write = WriteState::Idle(buf);
+
+                Poll::Ready(cqe_result(&entry).map(|_| ()))
+            }
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+
+    /// Flushes, then closes `fd`, which must be the descriptor this was created for, with a
+    /// `CLOSE` op. Later reads and writes fail with `EBADF`, rather than reaching whatever reuses
+    /// the number.
+    pub(crate) fn poll_close(
+        &mut self,
+        cx: &mut Context<'_>,
+        fd: &mut Option<OwnedFd>,
+    ) -> Poll<io::Result<()>> {
+        if let WriteState::Closing(op) = &mut self.write {
+            let (entry, _) = ready!(Pin::new(op).poll(cx));
+
+            self.write = WriteState::Idle(Vec::new());
+
+            return Poll::Ready(cqe_result(&entry).map(|_| ()));
+        }
+
+        ready!(self.poll_flush(cx))?;
+
+        let Some(owned) = fd.take() else {
+            return Poll::Ready(Ok(()));
+        };
+
+        self.fd = -1;
+        self.nonblocking = None;
+        self.read = ReadState::Idle(Vec::new());
+
+        let entry = io_uring::opcode::Close::new(io_uring::types::Fd(owned.as_raw_fd())).build();
+
+        // if the op can't be submitted the fd is still owned here, and dropping it closes it
+        let op = unsafe { submit_op(entry, ()) }?;
+
+        let _ = owned.into_raw_fd();
+
+        self.write = WriteState::Closing(op);
+
+        self.poll_close(cx, fd)
+    }
+}
+
+impl fmt::Debug for FdIo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FdIo")
+            .field("fd", &self.fd)
+            .field("readiness", &self.readiness)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Opens the terminal behind `fd` again as a new, non-blocking file description. `O_NONBLOCK` set on
+/// `fd` itself would also apply to every other process sharing its description, such as the shell.
+fn reopen_nonblocking(fd: RawFd) -> Option<OwnedFd> {
+    let mut pty = 0;
+
+    // reopening a pty master would create a new pty instead
+    if unsafe { libc::ioctl(fd, libc::TIOCGPTN, &mut pty) } == 0 {
+        return None;
+    }
+
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+
+    if flags == -1 {
+        return None;
+    }
+
+    let path = CString::new(format!("/proc/self/fd/{fd}")).unwrap();
+
+    let flags = flags & libc::O_ACCMODE | libc::O_NONBLOCK | libc::O_NOCTTY | libc::O_CLOEXEC;
+
+    match unsafe { libc::open(path.as_ptr(), flags) } {
+        -1 => None,
+        fd => Some(unsafe { OwnedFd::from_raw_fd(fd) }),
+    }
+}
+
+fn poll_op(fd: RawFd, events: libc::c_short) -> io::Result<Op<()>> {
+    let entry = io_uring::opcode::PollAdd::new(io_uring::types::Fd(fd), events as _).build();
+
+    unsafe { submit_op(entry, ()) }
+}
+
+fn syscall_result(res: isize) -> io::Result<usize> {
+    if res == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::TcpListener;
+    use crate::rt::Runtime;
+
+    #[test]
+    fn test_splice_tee() {
+        let mut runtime = Runtime::new(256).unwrap();
+
+        runtime.spawn(async {
+            let (tx_a, rx_a) = pipe::pipe().unwrap();
+            let (tx_b, rx_b) = pipe::pipe().unwrap();
+
+            let n = unsafe { libc::write(tx_a.as_raw_fd(), b"hello".as_ptr() as _, 5) };
+            assert_eq!(5, n);
+
+            // tee leaves the data in the source pipe
+            assert_eq!(5, tee(&rx_a, &tx_b, 64).await.unwrap());
+            assert_eq!(5, splice(&rx_a, &tx_b, 64).await.unwrap());
+
+            drop(tx_b);
+
+            let mut buf = [0; 16];
+            let n = unsafe { libc::read(rx_b.as_raw_fd(), buf.as_mut_ptr() as _, buf.len()) };
+            assert_eq!(b"hellohello", &buf[..n as usize]);
+
+            drop(tx_a);
+            assert_eq!(
+                0,
+                splice(&rx_a, &pipe::pipe().unwrap().0, 64).await.unwrap()
+            );
+        });
+
+        runtime.run().unwrap();
+    }
+
+    #[test]
+    fn test_tty_readiness() {
+        let master = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY) };
+        assert!(master >= 0);
+        let master = unsafe { OwnedFd::from_raw_fd(master) };
+
+        unsafe {
+            assert_eq!(0, libc::grantpt(master.as_raw_fd()));
+            assert_eq!(0, libc::unlockpt(master.as_raw_fd()));
+        }
+
+        let name = unsafe { std::ffi::CStr::from_ptr(libc::ptsname(master.as_raw_fd())) };
+        let slave = unsafe { libc::open(name.as_ptr(), libc::O_RDWR | libc::O_NOCTTY) };
+        assert!(slave >= 0);
+        let slave = unsafe { OwnedFd::from_raw_fd(slave) };
+
+        let flags = |fd: RawFd| unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        let (slave_fd, master_fd) = (slave.as_raw_fd(), master.as_raw_fd());
+        let (slave_flags, master_flags) = (flags(slave_fd), flags(master_fd));
+
+        let mut runtime = Runtime::new(256).unwrap();
+
+        runtime.spawn(async move {
+            let mut io = FdIo::new(slave_fd);
+            assert!(io.readiness && io.nonblocking.is_some());
+
+            let mut master = FdIo::new(master_fd);
+
+            let n = future::poll_fn(|cx| master.poll_write(cx, b"hello\n")).await;
+            assert_eq!(6, n.unwrap());
+
+            let mut buf = [0; 16];
+            let n = future::poll_fn(|cx| io.poll_read(cx, &mut buf)).await;
+            assert_eq!(b"hello\n", &buf[..n.unwrap()]);
+
+            // nobody reads the master, so this fills the pty and comes back short
+            let big = vec![b'x'; 1024 * 1024];
+            let n = future::poll_fn(|cx| io.poll_write(cx, &big)).await.unwrap();
+            assert!(n > 0 && n < big.len(), "{n}");
+        });
+
+        runtime.run().unwrap();
+
+        // the descriptions others may share were left blocking
+        assert_eq!(slave_flags, flags(slave_fd));
+        assert_eq!(master_flags, flags(master_fd));
+        assert_eq!(0, slave_flags & libc::O_NONBLOCK);
+    }
+
+    #[test]
+    fn test_copy_bidirectional() {
+        let mut runtime = Runtime::new(256).unwrap();
+
+        let upstream = TcpListener::bind("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+
+        let proxy = TcpListener::bind("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+
+        const LEN: usize = 1024 * 1024;
+
+        // echoes everything back, then closes
+        runtime.spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+
+            let mut buf = vec![0; 16 * 1024];
+
+            loop {
+                let n = stream.read(&mut buf).await.unwrap();
+
+                if n == 0 {
+                    break;
+                }
+
+                let mut written = 0;
+
+                while written < n {
+                    written += stream.write(&buf[written..n]).await.unwrap();
+                }
+            }
+        });
+
+        runtime.spawn(async move {
+            let (client, _) = proxy.accept().await.unwrap();
+            let server = TcpStream::connect(upstream_addr).await.unwrap();
+
+            let (to_server, to_client) = copy_bidirectional(&client, &server).await.unwrap();
+
+            assert_eq!(LEN as u64, to_server);
+            assert_eq!(LEN as u64, to_client);
+        });
+
+        // a plain blocking client on its own thread, writing and reading at the same time
+        let client = std::thread::spawn(move || {
+            use std::io::{Read, Write};
+
+            let mut writer = std::net::TcpStream::connect(proxy_addr).unwrap();
+            let mut reader = writer.try_clone().unwrap();
+
+            let data: Vec<u8> = (0..LEN).map(|i| i as u8).collect();
+            let expected = data.clone();
+
+            let send = std::thread::spawn(move || {
+                writer.write_all(&data).unwrap();
+                writer.shutdown(std::net::Shutdown::Write).unwrap();
+            });
+
+            let mut received = Vec::with_capacity(LEN);
+            reader.read_to_end(&mut received).unwrap();
+
+            send.join().unwrap();
+
+            assert!(received == expected);
+        });
+
+        runtime.run().unwrap();
+
+        client.join().unwrap();
+    }
+}
@@ -4,7 +4,14 @@ pub(crate) mod sys;
 
 pub mod rt;
 
+pub use pipe::pipe;
 pub use sys::{spawn, submit_op};
 
+pub mod fs;
 pub mod io;
 pub mod net;
+pub mod pipe;
+pub mod process;
+pub mod signal;
+
+pub(crate) mod time;
@@ -1,12 +1,23 @@
-use crate::io::Unsubmitted;
-use crate::submit_op;
+use crate::fs::File;
+use crate::io::{cqe_result, poll_ready, prepare_batch, splice_op, Unsubmitted, PIPE_CHUNK};
+use crate::sys::coop;
+use crate::{submit_op, sys, time};
+use futures::future::{self, Either};
+use futures::stream::FuturesUnordered;
+use futures::{pin_mut, StreamExt};
 use io_uring::cqueue;
+use io_uring::squeue::Flags;
 use socket2::{Domain, Protocol, SockAddr, Type};
-use std::io;
+use std::collections::VecDeque;
 use std::io::{Read, Write};
-use std::net::SocketAddr;
-use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::os::unix::net as unix;
+use std::path::Path;
 use std::ptr::null_mut;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{io, mem, ptr};
 
 pub struct TcpListener {
     inner: std::net::TcpListener,
@@ -19,82 +30,139 @@ pub struct TcpStream {
 
 impl TcpListener {
     pub fn bind(addr: SocketAddr, reuse_port: bool) -> io::Result<Self> {
-        let sock =
-            socket2::Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        let socket = TcpSocket::new_for_addr(addr)?;
 
-        sock.set_reuse_port(reuse_port)?;
-        sock.bind(&addr.into())?;
-        sock.listen(256)?;
-
-        let inner = unsafe { std::net::TcpListener::from_raw_fd(sock.into_raw_fd()) };
+        socket.set_reuseport(reuse_port)?;
+        socket.bind(addr)?;
+        socket.listen(256)
+    }
 
-        Ok(Self { inner })
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
     }
 
-    pub async fn accept(&self) -> io::Result<TcpStream> {
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
         let fd = io_uring::types::Fd(self.inner.as_raw_fd());
-        let entry = io_uring::opcode::Accept::new(fd, null_mut(), null_mut()).build();
 
-        let (entry, _) = unsafe { submit_op(entry, ()) }?.await;
+        // the kernel writes the peer address here, so it has to live as long as the op
+        let mut addr: Box<(libc::sockaddr_storage, libc::socklen_t)> = Box::new((
+            unsafe { mem::zeroed() },
+            mem::size_of::<libc::sockaddr_storage>() as _,
+        ));
 
-        let fd = entry.result();
+        let entry = io_uring::opcode::Accept::new(
+            fd,
+            &mut addr.0 as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut addr.1,
+        )
+        .build();
 
-        if fd != -1 {
-            let inner = unsafe { std::net::TcpStream::from_raw_fd(fd) };
+        let (entry, addr) = unsafe { submit_op(entry, addr) }?.await;
 
-            // needed for readiness io
-            inner.set_nonblocking(true)?;
+        let fd = cqe_result(&entry)?;
 
-            Ok(TcpStream { inner })
-        } else {
-            Err(io::Error::from_raw_os_error(fd))
-        }
+        let inner = unsafe { std::net::TcpStream::from_raw_fd(fd as _) };
+
+        // needed for readiness io
+        inner.set_nonblocking(true)?;
+
+        let (storage, len) = *addr;
+        let addr = unsafe { SockAddr::new(storage, len) }
+            .as_socket()
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "peer is not an inet socket")
+            })?;
+
+        Ok((TcpStream { inner }, addr))
     }
 }
 
 impl TcpStream {
-    pub async fn connect(addr: SocketAddr) -> io::Result<Self> {
-        let socket = socket2::Socket::new(
-            if addr.is_ipv4() {
-                Domain::IPV4
-            } else {
-                Domain::IPV6
-            },
-            Type::STREAM,
-            Some(Protocol::TCP),
-        )?;
-
-        let fd = io_uring::types::Fd(socket.as_raw_fd());
+    /// Connects to the given address, trying each resolved address in turn.
+    ///
+    /// When the input resolves to several addresses, attempts are interleaved by address family and
+    /// started [`CONNECTION_ATTEMPT_DELAY`] apart (RFC 8305 "happy eyeballs"), so a dead IPv6 route
+    /// does not hold up a working IPv4 one. An attempt that fails starts the next one right away.
+    /// The first attempt to succeed wins, and if every attempt fails the last error is returned.
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let addrs = interleave_families(lookup_host(addr).await?.collect());
+
+        let mut remaining = addrs.into_iter().peekable();
+        let mut attempts = FuturesUnordered::new();
+        let mut last_err = None;
 
-        let sock: Box<SockAddr> = Box::new(addr.into());
+        loop {
+            if attempts.is_empty() {
+                match remaining.next() {
+                    Some(addr) => attempts.push(connect_addr(addr, None)),
+                    None => {
+                        return Err(last_err.unwrap_or_else(|| {
+                            io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "could not resolve to any addresses",
+                            )
+                        }))
+                    }
+                }
+            }
 
-        let entry = io_uring::opcode::Connect::new(fd, sock.as_ptr(), sock.len()).build();
+            let res = if remaining.peek().is_some() {
+                let delay = time::sleep(CONNECTION_ATTEMPT_DELAY);
+                pin_mut!(delay);
 
-        let (entry, _) = unsafe { submit_op(entry, sock) }?.await;
+                match future::select(attempts.next(), delay).await {
+                    Either::Left((res, _)) => res,
+                    Either::Right((res, _)) => {
+                        res?;
 
-        let ret = entry.result();
+                        // the in-flight attempts are taking too long, start the next one alongside
+                        attempts.push(connect_addr(remaining.next().unwrap(), None));
 
-        if ret != -1 {
-            let inner = unsafe { std::net::TcpStream::from_raw_fd(socket.into_raw_fd()) };
+                        continue;
+                    }
+                }
+            } else {
+                attempts.next().await
+            };
 
-            // needed for readiness io
-            inner.set_nonblocking(true)?;
+            match res {
+                Some(Ok(stream)) => return Ok(stream),
+                Some(Err(e)) => {
+                    last_err = Some(e);
 
-            Ok(TcpStream { inner })
-        } else {
-            Err(io::Error::last_os_error())
+                    // a failed attempt frees its turn, so the next one starts without waiting out
+                    // the delay
+                    if let Some(addr) = remaining.next() {
+                        attempts.push(connect_addr(addr, None));
+                    }
+                }
+                None => {}
+            }
         }
     }
 
+    /// Connects to `addr`, failing with [`io::ErrorKind::TimedOut`] if the connection is not
+    /// established within `timeout`.
+    pub async fn connect_timeout(addr: SocketAddr, timeout: Duration) -> io::Result<Self> {
+        connect_addr(addr, Some(timeout)).await
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
     pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        coop::proceed().await;
+
         loop {
             match self.inner.read(buf) {
                 Ok(len) => return Ok(len),
                 Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    let fd = io_uring::types::Fd(self.inner.as_raw_fd());
-                    let entry = io_uring::opcode::PollAdd::new(fd, libc::POLLIN as _).build();
-
-                    unsafe { submit_op(entry, ())?.await };
+                    poll_ready(self.inner.as_raw_fd(), libc::POLLIN).await?;
                 }
                 Err(e) => {
                     return Err(e);
@@ -104,14 +172,13 @@ impl TcpStream {
     }
 
     pub async fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        coop::proceed().await;
+
         loop {
             match self.inner.write(buf) {
                 Ok(len) => return Ok(len),
                 Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    let fd = io_uring::types::Fd(self.inner.as_raw_fd());
-                    let entry = io_uring::opcode::PollAdd::new(fd, libc::POLLOUT as _).build();
-
-                    unsafe { submit_op(entry, ())?.await };
+                    poll_ready(self.inner.as_raw_fd(), libc::POLLOUT).await?;
                 }
                 Err(e) => {
                     return Err(e);
@@ -120,7 +187,93 @@ impl TcpStream {
         }
     }
 
+    /// Sends up to `len` bytes of `file` starting at `offset`, returning how many were sent. This
+    /// is only less than `len` if the file ends first.
+    ///
+    /// The data is spliced from the file into a pipe from the worker's cache and on into the
+    /// socket, with both splices linked into one submission, so it never reaches userspace.
+    pub async fn send_file(&mut self, file: &File, offset: u64, len: usize) -> io::Result<usize> {
+        let sock = self.inner.as_raw_fd();
+        let file = file.as_raw_fd();
+
+        let pipe = sys::take_pipe()?;
+        let (pipe_tx, pipe_rx) = (pipe.0.as_raw_fd(), pipe.1.as_raw_fd());
+
+        let mut offset = offset;
+        let mut sent = 0;
+        let mut in_pipe = 0;
+
+        while sent < len {
+            // the socket took less than the last round moved into the pipe
+            if in_pipe > 0 {
+                let drain = unsafe { splice_op(pipe_rx, -1, sock, -1, in_pipe, 0, pipe.clone()) };
+
+                match drain.submit()?.await {
+                    Ok(n) => {
+                        in_pipe -= n as u32;
+                        sent += n;
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        poll_ready(sock, libc::POLLOUT).await?
+                    }
+                    Err(e) => return Err(e),
+                }
+
+                continue;
+            }
+
+            let chunk = (len - sent).min(PIPE_CHUNK as usize) as u32;
+
+            prepare_batch(2)?;
+
+            let mut fill =
+                unsafe { splice_op(file, offset as i64, pipe_tx, -1, chunk, 0, pipe.clone()) };
+
+            // a short splice counts as a failure for links, so only a hard link still runs the
+            // drain
+            unsafe { fill.apply_flags(Flags::IO_HARDLINK) };
+
+            let drain = unsafe {
+                splice_op(
+                    pipe_rx,
+                    -1,
+                    sock,
+                    -1,
+                    chunk,
+                    libc::SPLICE_F_NONBLOCK,
+                    pipe.clone(),
+                )
+            };
+
+            let (filled, drained) = future::join(fill.submit()?, drain.submit()?).await;
+
+            let drained = match drained {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => 0,
+                Err(e) => return Err(e),
+            };
+
+            sent += drained;
+
+            match filled? {
+                0 => break,
+                n => {
+                    offset += n as u64;
+                    in_pipe = (n - drained) as u32;
+                }
+            }
+        }
+
+        // only an empty pipe can be reused, anything else is dropped with the error
+        sys::return_pipe(pipe);
+
+        Ok(sent)
+    }
+
+    /// # Safety
+    /// The buffer must not move or be resized while the op is in flight.
     // todo this is unsound
+    #[allow(clippy::type_complexity)]
     pub unsafe fn read_owned<T: AsMut<[u8]> + 'static + Unpin>(
         &mut self,
         mut buf: T,
@@ -134,19 +287,18 @@ impl TcpStream {
                 .build();
 
         let post_op = |entry: cqueue::Entry, buf| {
-            let len = entry.result();
+            let len = cqe_result(&entry)?;
 
-            if len != -1 {
-                Ok((len as usize, buf))
-            } else {
-                Err(io::Error::from_raw_os_error(len))
-            }
+            Ok((len as usize, buf))
         };
 
         Unsubmitted::from_raw(entry, buf, post_op)
     }
 
+    /// # Safety
+    /// The buffer must not move or be resized while the op is in flight.
     // todo this is unsound
+    #[allow(clippy::type_complexity)]
     pub unsafe fn write_owned<T: AsRef<[u8]> + 'static + Unpin>(
         &mut self,
         buf: T,
@@ -160,100 +312,1302 @@ impl TcpStream {
                 .build();
 
         let post_op = |entry: cqueue::Entry, buf| {
-            let len = entry.result();
+            let len = cqe_result(&entry)?;
 
-            if len != -1 {
-                Ok((len as usize, buf))
-            } else {
-                Err(io::Error::from_raw_os_error(len))
-            }
+            Ok((len as usize, buf))
         };
 
         Unsubmitted::from_raw(entry, buf, post_op)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::rt::Runtime;
+impl AsRawFd for TcpStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
 
-    #[test]
-    fn test_tcp_readiness() {
-        let mut runtime = Runtime::new(256).unwrap();
+impl AsRawFd for TcpListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
 
-        runtime.spawn(async {
-            let listener = TcpListener::bind("127.0.0.1:8080".parse().unwrap()).unwrap();
+/// A TCP socket that has not yet been converted to a [`TcpListener`] or [`TcpStream`], used to
+/// configure socket options before binding, listening or connecting.
+pub struct TcpSocket {
+    inner: socket2::Socket,
+}
 
-            let mut stream = listener.accept().await.unwrap();
+impl TcpSocket {
+    pub fn new_v4() -> io::Result<Self> {
+        Self::new(Domain::IPV4)
+    }
 
-            let mut buf = [0; 64];
+    pub fn new_v6() -> io::Result<Self> {
+        Self::new(Domain::IPV6)
+    }
 
-            let len = stream.read(&mut buf).await.unwrap();
+    fn new_for_addr(addr: SocketAddr) -> io::Result<Self> {
+        Self::new(Domain::for_address(addr))
+    }
 
-            assert_eq!(b"hello", &buf[..len]);
+    fn new(domain: Domain) -> io::Result<Self> {
+        let inner = socket2::Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
 
-            stream.write(b"world").await.unwrap();
-        });
+        Ok(Self { inner })
+    }
 
-        runtime.spawn(async {
-            let mut stream = TcpStream::connect("127.0.0.1:8080".parse().unwrap())
-                .await
-                .unwrap();
+    pub fn set_reuseaddr(&self, reuseaddr: bool) -> io::Result<()> {
+        self.inner.set_reuse_address(reuseaddr)
+    }
 
-            stream.write(b"hello").await.unwrap();
+    pub fn reuseaddr(&self) -> io::Result<bool> {
+        self.inner.reuse_address()
+    }
 
-            let mut buf = [0; 64];
+    pub fn set_reuseport(&self, reuseport: bool) -> io::Result<()> {
+        self.inner.set_reuse_port(reuseport)
+    }
 
-            let len = stream.read(&mut buf).await.unwrap();
+    pub fn reuseport(&self) -> io::Result<bool> {
+        self.inner.reuse_port()
+    }
 
-            assert_eq!(b"world", &buf[..len]);
-        });
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.inner.set_nodelay(nodelay)
+    }
 
-        runtime.run().unwrap();
+    pub fn nodelay(&self) -> io::Result<bool> {
+        self.inner.nodelay()
     }
 
-    #[test]
-    fn test_tcp_owned() {
-        let mut runtime = Runtime::new(256).unwrap();
+    pub fn set_keepalive(&self, keepalive: bool) -> io::Result<()> {
+        self.inner.set_keepalive(keepalive)
+    }
 
-        runtime.spawn(async {
-            let listener = TcpListener::bind("127.0.0.1:9000".parse().unwrap()).unwrap();
+    pub fn keepalive(&self) -> io::Result<bool> {
+        self.inner.keepalive()
+    }
 
-            let mut stream = listener.accept().await.unwrap();
+    pub fn set_send_buffer_size(&self, size: u32) -> io::Result<()> {
+        self.inner.set_send_buffer_size(size as usize)
+    }
 
-            let buf = vec![0; 64];
+    pub fn send_buffer_size(&self) -> io::Result<u32> {
+        self.inner.send_buffer_size().map(|size| size as u32)
+    }
 
-            let (len, buf) = unsafe { stream.read_owned(buf).submit().unwrap().await.unwrap() };
+    pub fn set_recv_buffer_size(&self, size: u32) -> io::Result<()> {
+        self.inner.set_recv_buffer_size(size as usize)
+    }
 
-            assert_eq!(b"hello", &buf[..len]);
+    pub fn recv_buffer_size(&self) -> io::Result<u32> {
+        self.inner.recv_buffer_size().map(|size| size as u32)
+    }
 
-            unsafe {
-                stream
-                    .write_owned(b"world")
-                    .submit()
-                    .unwrap()
-                    .await
-                    .unwrap()
-            };
-        });
+    /// Sets `IPV6_V6ONLY`. Only valid on sockets created with [`TcpSocket::new_v6`].
+    pub fn set_only_v6(&self, only_v6: bool) -> io::Result<()> {
+        self.inner.set_only_v6(only_v6)
+    }
 
-        runtime.spawn(async {
-            let mut stream = TcpStream::connect("127.0.0.1:9000".parse().unwrap())
-                .await
-                .unwrap();
+    pub fn only_v6(&self) -> io::Result<bool> {
+        self.inner.only_v6()
+    }
 
-            unsafe { stream.write_owned(b"hello") }
-                .submit()
-                .unwrap()
-                .await
-                .unwrap();
+    /// Enables server-side TCP fast open, allowing up to `queue_len` pending fast open requests.
+    pub fn set_tcp_fastopen(&self, queue_len: u32) -> io::Result<()> {
+        setsockopt(
+            self.inner.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN,
+            queue_len as libc::c_int,
+        )
+    }
 
-            let buf = vec![0; 64];
+    pub fn tcp_fastopen(&self) -> io::Result<u32> {
+        getsockopt(
+            self.inner.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN,
+        )
+        .map(|queue_len| queue_len as u32)
+    }
 
-            let (len, buf) = unsafe { stream.read_owned(buf).submit().unwrap().await.unwrap() };
+    /// Enables client-side TCP fast open, deferring the SYN until the first write.
+    pub fn set_tcp_fastopen_connect(&self, enabled: bool) -> io::Result<()> {
+        setsockopt(
+            self.inner.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN_CONNECT,
+            enabled as libc::c_int,
+        )
+    }
 
-            assert_eq!(b"world", &buf[..len]);
+    pub fn tcp_fastopen_connect(&self) -> io::Result<bool> {
+        getsockopt(
+            self.inner.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN_CONNECT,
+        )
+        .map(|enabled| enabled != 0)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()?.as_socket().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "socket is not an inet socket")
+        })
+    }
+
+    /// Binds the socket, which for a connecting socket picks the local address used.
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<()> {
+        self.inner.bind(&addr.into())
+    }
+
+    pub fn listen(self, backlog: i32) -> io::Result<TcpListener> {
+        self.inner.listen(backlog)?;
+
+        let inner = unsafe { std::net::TcpListener::from_raw_fd(self.inner.into_raw_fd()) };
+
+        Ok(TcpListener { inner })
+    }
+
+    pub async fn connect(self, addr: SocketAddr) -> io::Result<TcpStream> {
+        self.connect_with_timeout(addr, None).await
+    }
+
+    async fn connect_with_timeout(
+        self,
+        addr: SocketAddr,
+        timeout: Option<Duration>,
+    ) -> io::Result<TcpStream> {
+        let fd = io_uring::types::Fd(self.inner.as_raw_fd());
+
+        let sock: Box<SockAddr> = Box::new(addr.into());
+
+        let entry = io_uring::opcode::Connect::new(fd, sock.as_ptr(), sock.len()).build();
+
+        let res = match timeout {
+            Some(timeout) => {
+                let ts = Box::new(time::timespec(timeout));
+                let timeout_entry = io_uring::opcode::LinkTimeout::new(&*ts).build();
+
+                // the linked pair must land in the same submission
+                prepare_batch(2)?;
+
+                let op = unsafe { submit_op(entry.flags(Flags::IO_LINK), sock) }?;
+                let _timeout_op = unsafe { submit_op(timeout_entry, ts) }?;
+
+                let (entry, _) = op.await;
+
+                match cqe_result(&entry) {
+                    Err(e) if e.raw_os_error() == Some(libc::ECANCELED) => Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "connection timed out",
+                    )),
+                    res => res,
+                }
+            }
+            None => {
+                let (entry, _) = unsafe { submit_op(entry, sock) }?.await;
+
+                cqe_result(&entry)
+            }
+        };
+
+        res?;
+
+        let inner = unsafe { std::net::TcpStream::from_raw_fd(self.inner.into_raw_fd()) };
+
+        // needed for readiness io
+        inner.set_nonblocking(true)?;
+
+        Ok(TcpStream { inner })
+    }
+}
+
+/// Types which can be resolved to one or more [`SocketAddr`]s, either immediately or through
+/// [`lookup_host`].
+///
+/// This mirrors [`std::net::ToSocketAddrs`], except that host names are resolved on the blocking
+/// pool instead of the calling thread.
+pub trait ToSocketAddrs: sealed::ToSocketAddrsPriv {}
+
+mod sealed {
+    use std::io;
+    use std::net::SocketAddr;
+
+    pub enum Resolve {
+        Ready(Vec<SocketAddr>),
+        Lookup(String, u16),
+    }
+
+    pub trait ToSocketAddrsPriv {
+        fn resolve(&self) -> io::Result<Resolve>;
+    }
+}
+
+use sealed::{Resolve, ToSocketAddrsPriv};
+
+macro_rules! ready_addrs {
+    ($($ty:ty),*) => {
+        $(
+            impl ToSocketAddrs for $ty {}
+
+            impl ToSocketAddrsPriv for $ty {
+                fn resolve(&self) -> io::Result<Resolve> {
+                    Ok(Resolve::Ready(std::net::ToSocketAddrs::to_socket_addrs(self)?.collect()))
+                }
+            }
+        )*
+    };
+}
+
+ready_addrs!(
+    SocketAddr,
+    SocketAddrV4,
+    SocketAddrV6,
+    (IpAddr, u16),
+    (Ipv4Addr, u16),
+    (Ipv6Addr, u16)
+);
+
+impl ToSocketAddrs for [SocketAddr] {}
+
+impl ToSocketAddrsPriv for [SocketAddr] {
+    fn resolve(&self) -> io::Result<Resolve> {
+        Ok(Resolve::Ready(self.to_vec()))
+    }
+}
+
+impl ToSocketAddrs for str {}
+
+impl ToSocketAddrsPriv for str {
+    fn resolve(&self) -> io::Result<Resolve> {
+        if let Ok(addr) = self.parse() {
+            return Ok(Resolve::Ready(vec![addr]));
+        }
+
+        let (host, port) = self
+            .rsplit_once(':')
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid socket address"))?;
+
+        let port = port
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid port value"))?;
+
+        (host, port).resolve()
+    }
+}
+
+impl ToSocketAddrs for (&str, u16) {}
+
+impl ToSocketAddrsPriv for (&str, u16) {
+    fn resolve(&self) -> io::Result<Resolve> {
+        let (host, port) = *self;
+
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(Resolve::Ready(vec![SocketAddr::new(ip, port)]));
+        }
+
+        Ok(Resolve::Lookup(host.to_owned(), port))
+    }
+}
+
+impl ToSocketAddrs for String {}
+
+impl ToSocketAddrsPriv for String {
+    fn resolve(&self) -> io::Result<Resolve> {
+        self.as_str().resolve()
+    }
+}
+
+impl ToSocketAddrs for (String, u16) {}
+
+impl ToSocketAddrsPriv for (String, u16) {
+    fn resolve(&self) -> io::Result<Resolve> {
+        (self.0.as_str(), self.1).resolve()
+    }
+}
+
+impl<T: ToSocketAddrs + ?Sized> ToSocketAddrs for &T {}
+
+impl<T: ToSocketAddrsPriv + ?Sized> ToSocketAddrsPriv for &T {
+    fn resolve(&self) -> io::Result<Resolve> {
+        (**self).resolve()
+    }
+}
+
+/// Resolves a host name and port to socket addresses, in place of the system resolver. Tests set
+/// one with [`Builder::resolver`](crate::rt::Builder::resolver) to serve names from a stub.
+pub(crate) type Resolver = Arc<dyn Fn(&str, u16) -> io::Result<Vec<SocketAddr>> + Send + Sync>;
+
+/// Resolves `host` to the socket addresses it refers to.
+///
+/// Addresses given literally are returned straight away; host names are passed to the system
+/// resolver (`getaddrinfo`) on the runtime's blocking pool, so the ring thread keeps running other
+/// tasks while the lookup is in progress.
+pub async fn lookup_host<T: ToSocketAddrs>(
+    host: T,
+) -> io::Result<impl Iterator<Item = SocketAddr>> {
+    let addrs = match host.resolve()? {
+        Resolve::Ready(addrs) => addrs,
+        Resolve::Lookup(host, port) => {
+            let resolver = sys::with_context(|context| context.resolver.clone());
+
+            sys::run_blocking(move || match resolver {
+                Some(resolver) => resolver(&host, port),
+                None => std::net::ToSocketAddrs::to_socket_addrs(&(host.as_str(), port))
+                    .map(|addrs| addrs.collect()),
+            })
+            .await?
+        }
+    };
+
+    Ok(addrs.into_iter())
+}
+
+/// How long a connection attempt gets before the next address is tried alongside it.
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+async fn connect_addr(addr: SocketAddr, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    TcpSocket::new_for_addr(addr)?
+        .connect_with_timeout(addr, timeout)
+        .await
+}
+
+/// Reorders `addrs` so that address families alternate, starting with the family of the first
+/// address, while otherwise keeping the resolver's preference order.
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_v6 = match addrs.first() {
+        Some(addr) => addr.is_ipv6(),
+        None => return addrs,
+    };
+
+    let (mut preferred, mut other): (VecDeque<_>, VecDeque<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_is_v6);
+
+    let mut out = Vec::with_capacity(preferred.len() + other.len());
+
+    loop {
+        match (preferred.pop_front(), other.pop_front()) {
+            (None, None) => return out,
+            (a, b) => out.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Credentials of the process on the other end of a unix socket, as reported by `SO_PEERCRED`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UCred {
+    pub pid: libc::pid_t,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+pub struct UnixListener {
+    inner: std::os::unix::net::UnixListener,
+}
+
+#[derive(Debug)]
+pub struct UnixStream {
+    inner: std::os::unix::net::UnixStream,
+}
+
+#[derive(Debug)]
+pub struct UnixDatagram {
+    inner: std::os::unix::net::UnixDatagram,
+}
+
+impl UnixListener {
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let inner = std::os::unix::net::UnixListener::bind(path)?;
+
+        Ok(Self { inner })
+    }
+
+    pub fn local_addr(&self) -> io::Result<unix::SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    pub async fn accept(&self) -> io::Result<UnixStream> {
+        let fd = io_uring::types::Fd(self.inner.as_raw_fd());
+        let entry = io_uring::opcode::Accept::new(fd, null_mut(), null_mut()).build();
+
+        let (entry, _) = unsafe { submit_op(entry, ()) }?.await;
+
+        let fd = cqe_result(&entry)?;
+
+        let inner = unsafe { std::os::unix::net::UnixStream::from_raw_fd(fd as _) };
+
+        // needed for readiness io
+        inner.set_nonblocking(true)?;
+
+        Ok(UnixStream { inner })
+    }
+}
+
+impl UnixStream {
+    pub async fn connect<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let socket = socket2::Socket::new(Domain::UNIX, Type::STREAM, None)?;
+
+        let fd = io_uring::types::Fd(socket.as_raw_fd());
+
+        let sock: Box<SockAddr> = Box::new(SockAddr::unix(path)?);
+
+        let entry = io_uring::opcode::Connect::new(fd, sock.as_ptr(), sock.len()).build();
+
+        let (entry, _) = unsafe { submit_op(entry, sock) }?.await;
+
+        cqe_result(&entry)?;
+
+        let inner = unsafe { std::os::unix::net::UnixStream::from_raw_fd(socket.into_raw_fd()) };
+
+        // needed for readiness io
+        inner.set_nonblocking(true)?;
+
+        Ok(UnixStream { inner })
+    }
+
+    /// Creates an unnamed pair of connected sockets.
+    pub fn pair() -> io::Result<(Self, Self)> {
+        let (a, b) = std::os::unix::net::UnixStream::pair()?;
+
+        a.set_nonblocking(true)?;
+        b.set_nonblocking(true)?;
+
+        Ok((Self { inner: a }, Self { inner: b }))
+    }
+
+    pub fn local_addr(&self) -> io::Result<unix::SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<unix::SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    pub fn peer_cred(&self) -> io::Result<UCred> {
+        peer_cred(self.inner.as_raw_fd())
+    }
+
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        coop::proceed().await;
+
+        loop {
+            match self.inner.read(buf) {
+                Ok(len) => return Ok(len),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    poll_ready(self.inner.as_raw_fd(), libc::POLLIN).await?;
+                }
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    pub async fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        coop::proceed().await;
+
+        loop {
+            match self.inner.write(buf) {
+                Ok(len) => return Ok(len),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    poll_ready(self.inner.as_raw_fd(), libc::POLLOUT).await?;
+                }
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Sends `buf` along with `fds` as `SCM_RIGHTS` ancillary data.
+    ///
+    /// The descriptors are duplicated into the receiving process, so the caller keeps ownership
+    /// of its copies.
+    pub async fn send_with_fds(&mut self, buf: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+        send_with_fds(self.inner.as_raw_fd(), buf, fds).await
+    }
+
+    /// Receives into `buf`, accepting up to `max_fds` file descriptors passed via `SCM_RIGHTS`.
+    ///
+    /// Received descriptors are opened with `O_CLOEXEC`. If more were sent than fit, the kernel
+    /// drops the rest and this fails, closing the ones that did arrive.
+    pub async fn recv_with_fds(
+        &mut self,
+        buf: &mut [u8],
+        max_fds: usize,
+    ) -> io::Result<(usize, Vec<OwnedFd>)> {
+        recv_with_fds(self.inner.as_raw_fd(), buf, max_fds).await
+    }
+}
+
+impl UnixDatagram {
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let inner = std::os::unix::net::UnixDatagram::bind(path)?;
+
+        inner.set_nonblocking(true)?;
+
+        Ok(Self { inner })
+    }
+
+    pub fn unbound() -> io::Result<Self> {
+        let inner = std::os::unix::net::UnixDatagram::unbound()?;
+
+        inner.set_nonblocking(true)?;
+
+        Ok(Self { inner })
+    }
+
+    /// Creates an unnamed pair of connected sockets.
+    pub fn pair() -> io::Result<(Self, Self)> {
+        let (a, b) = std::os::unix::net::UnixDatagram::pair()?;
+
+        a.set_nonblocking(true)?;
+        b.set_nonblocking(true)?;
+
+        Ok((Self { inner: a }, Self { inner: b }))
+    }
+
+    /// Sets the default destination for `send` and the only source accepted by `recv`.
+    pub fn connect<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.inner.connect(path)
+    }
+
+    pub fn local_addr(&self) -> io::Result<unix::SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<unix::SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    pub fn peer_cred(&self) -> io::Result<UCred> {
+        peer_cred(self.inner.as_raw_fd())
+    }
+
+    pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        coop::proceed().await;
+
+        loop {
+            match self.inner.send(buf) {
+                Ok(len) => return Ok(len),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    poll_ready(self.inner.as_raw_fd(), libc::POLLOUT).await?;
+                }
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    pub async fn send_to<P: AsRef<Path>>(&self, buf: &[u8], path: P) -> io::Result<usize> {
+        coop::proceed().await;
+
+        loop {
+            match self.inner.send_to(buf, path.as_ref()) {
+                Ok(len) => return Ok(len),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    poll_ready(self.inner.as_raw_fd(), libc::POLLOUT).await?;
+                }
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        coop::proceed().await;
+
+        loop {
+            match self.inner.recv(buf) {
+                Ok(len) => return Ok(len),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    poll_ready(self.inner.as_raw_fd(), libc::POLLIN).await?;
+                }
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, unix::SocketAddr)> {
+        coop::proceed().await;
+
+        loop {
+            match self.inner.recv_from(buf) {
+                Ok(res) => return Ok(res),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    poll_ready(self.inner.as_raw_fd(), libc::POLLIN).await?;
+                }
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Sends a datagram carrying `fds` as `SCM_RIGHTS` ancillary data to the connected peer.
+    pub async fn send_with_fds(&self, buf: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+        send_with_fds(self.inner.as_raw_fd(), buf, fds).await
+    }
+
+    /// Receives a datagram, accepting up to `max_fds` file descriptors passed via `SCM_RIGHTS`.
+    ///
+    /// Fails if more descriptors were sent than fit, as [`UnixStream::recv_with_fds`] does.
+    pub async fn recv_with_fds(
+        &self,
+        buf: &mut [u8],
+        max_fds: usize,
+    ) -> io::Result<(usize, Vec<OwnedFd>)> {
+        recv_with_fds(self.inner.as_raw_fd(), buf, max_fds).await
+    }
+}
+
+fn setsockopt(
+    fd: RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const _,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn getsockopt(fd: RawFd, level: libc::c_int, name: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            &mut value as *mut libc::c_int as *mut _,
+            &mut len,
+        )
+    };
+
+    if ret == 0 {
+        Ok(value)
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn peer_cred(fd: RawFd) -> io::Result<UCred> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut _,
+            &mut len,
+        )
+    };
+
+    if ret == 0 {
+        Ok(UCred {
+            pid: cred.pid,
+            uid: cred.uid,
+            gid: cred.gid,
+        })
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Everything a `SENDMSG`/`RECVMSG` op points at, boxed so the pointers in `hdr` stay valid
+/// while the op is in flight (or cancelled and waiting on its completion).
+struct MsgState {
+    hdr: libc::msghdr,
+    iov: libc::iovec,
+    buf: Vec<u8>,
+    // u64 storage keeps the control buffer aligned for `cmsghdr`
+    control: Vec<u64>,
+}
+
+impl MsgState {
+    fn new(buf: Vec<u8>, max_fds: usize) -> Box<Self> {
+        let space = unsafe { libc::CMSG_SPACE((max_fds * mem::size_of::<RawFd>()) as _) } as usize;
+        let words = space.div_ceil(mem::size_of::<u64>());
+
+        let mut state = Box::new(Self {
+            hdr: unsafe { mem::zeroed() },
+            iov: libc::iovec {
+                iov_base: null_mut(),
+                iov_len: 0,
+            },
+            buf,
+            control: vec![0; words],
+        });
+
+        state.iov.iov_base = state.buf.as_mut_ptr() as *mut _;
+        state.iov.iov_len = state.buf.len();
+
+        state.hdr.msg_iov = &mut state.iov;
+        state.hdr.msg_iovlen = 1;
+
+        if max_fds > 0 {
+            state.hdr.msg_control = state.control.as_mut_ptr() as *mut _;
+            state.hdr.msg_controllen = space as _;
+        }
+
+        state
+    }
+}
+
+async fn send_with_fds(fd: RawFd, buf: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+    let state = MsgState::new(buf.to_vec(), fds.len());
+
+    if !fds.is_empty() {
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&state.hdr);
+
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of_val(fds) as _) as _;
+
+            ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+        }
+    }
+
+    let entry = io_uring::opcode::SendMsg::new(io_uring::types::Fd(fd), &state.hdr).build();
+
+    let (entry, _) = unsafe { submit_op(entry, state) }?.await;
+
+    cqe_result(&entry).map(|len| len as usize)
+}
+
+async fn recv_with_fds(
+    fd: RawFd,
+    buf: &mut [u8],
+    max_fds: usize,
+) -> io::Result<(usize, Vec<OwnedFd>)> {
+    let mut state = MsgState::new(vec![0; buf.len()], max_fds);
+
+    let entry = io_uring::opcode::RecvMsg::new(io_uring::types::Fd(fd), &mut state.hdr)
+        .flags(libc::MSG_CMSG_CLOEXEC as _)
+        .build();
+
+    let (entry, state) = unsafe { submit_op(entry, state) }?.await;
+
+    // collect descriptors before looking at the result so none are leaked on a short read
+    let mut fds = Vec::new();
+
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&state.hdr);
+
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+
+                for i in 0..len / mem::size_of::<RawFd>() {
+                    fds.push(OwnedFd::from_raw_fd(ptr::read_unaligned(data.add(i))));
+                }
+            }
+
+            cmsg = libc::CMSG_NXTHDR(&state.hdr, cmsg);
+        }
+    }
+
+    let len = cqe_result(&entry)? as usize;
+
+    // the kernel closed whatever did not fit, so the caller can't rely on the ones it got
+    if state.hdr.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "more file descriptors were sent than max_fds allows",
+        ));
+    }
+
+    buf[..len].copy_from_slice(&state.buf[..len]);
+
+    Ok((len, fds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rt::Runtime;
+    use std::time::Instant;
+
+    #[test]
+    fn test_tcp_readiness() {
+        let mut runtime = Runtime::new(256).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        runtime.spawn(async move {
+            let (mut stream, peer) = listener.accept().await.unwrap();
+
+            assert_eq!(peer, stream.peer_addr().unwrap());
+
+            let mut buf = [0; 64];
+
+            let len = stream.read(&mut buf).await.unwrap();
+
+            assert_eq!(b"hello", &buf[..len]);
+
+            stream.write(b"world").await.unwrap();
+        });
+
+        runtime.spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+
+            assert_eq!(addr, stream.peer_addr().unwrap());
+
+            stream.write(b"hello").await.unwrap();
+
+            let mut buf = [0; 64];
+
+            let len = stream.read(&mut buf).await.unwrap();
+
+            assert_eq!(b"world", &buf[..len]);
+        });
+
+        runtime.run().unwrap();
+    }
+
+    #[test]
+    fn test_tcp_owned() {
+        let mut runtime = Runtime::new(256).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        runtime.spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let buf = vec![0; 64];
+
+            let (len, buf) = unsafe { stream.read_owned(buf).submit().unwrap().await.unwrap() };
+
+            assert_eq!(b"hello", &buf[..len]);
+
+            unsafe {
+                stream
+                    .write_owned(b"world")
+                    .submit()
+                    .unwrap()
+                    .await
+                    .unwrap()
+            };
+        });
+
+        runtime.spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+
+            unsafe { stream.write_owned(b"hello") }
+                .submit()
+                .unwrap()
+                .await
+                .unwrap();
+
+            let buf = vec![0; 64];
+
+            let (len, buf) = unsafe { stream.read_owned(buf).submit().unwrap().await.unwrap() };
+
+            assert_eq!(b"world", &buf[..len]);
+        });
+
+        runtime.run().unwrap();
+    }
+
+    #[test]
+    fn test_unix_stream() {
+        let path =
+            std::env::temp_dir().join(format!("urt-unix-stream-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut runtime = Runtime::new(256).unwrap();
+
+        let listener = UnixListener::bind(&path).unwrap();
+
+        runtime.spawn(async move {
+            let mut stream = listener.accept().await.unwrap();
+
+            assert_eq!(
+                std::process::id() as libc::pid_t,
+                stream.peer_cred().unwrap().pid
+            );
+
+            let mut buf = [0; 64];
+
+            let len = stream.read(&mut buf).await.unwrap();
+
+            assert_eq!(b"hello", &buf[..len]);
+
+            stream.write(b"world").await.unwrap();
+        });
+
+        let client_path = path.clone();
+
+        runtime.spawn(async move {
+            let mut stream = UnixStream::connect(&client_path).await.unwrap();
+
+            stream.write(b"hello").await.unwrap();
+
+            let mut buf = [0; 64];
+
+            let len = stream.read(&mut buf).await.unwrap();
+
+            assert_eq!(b"world", &buf[..len]);
+        });
+
+        runtime.run().unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_unix_fd_passing() {
+        let mut runtime = Runtime::new(256).unwrap();
+
+        let (mut a, mut b) = UnixStream::pair().unwrap();
+        let (da, db) = UnixDatagram::pair().unwrap();
+
+        runtime.spawn(async move {
+            let (mut tx, mut rx) = std::os::unix::net::UnixStream::pair().unwrap();
+
+            a.send_with_fds(b"fd", &[tx.as_raw_fd()]).await.unwrap();
+            da.send_with_fds(b"fd", &[rx.as_raw_fd()]).await.unwrap();
+
+            let mut buf = [0; 64];
+
+            let (len, fds) = b.recv_with_fds(&mut buf, 4).await.unwrap();
+
+            assert_eq!(b"fd", &buf[..len]);
+            assert_eq!(1, fds.len());
+
+            let (len, fds2) = db.recv_with_fds(&mut buf, 4).await.unwrap();
+
+            assert_eq!(b"fd", &buf[..len]);
+            assert_eq!(1, fds2.len());
+
+            // both received descriptors refer to the original pair
+            let mut sent = std::fs::File::from(fds.into_iter().next().unwrap());
+            let mut received = std::fs::File::from(fds2.into_iter().next().unwrap());
+
+            sent.write_all(b"ping").unwrap();
+            rx.read_exact(&mut buf[..4]).unwrap();
+            assert_eq!(b"ping", &buf[..4]);
+
+            tx.write_all(b"pong").unwrap();
+            received.read_exact(&mut buf[..4]).unwrap();
+            assert_eq!(b"pong", &buf[..4]);
+
+            // the room for one descriptor is padded to fit two
+            let three = [tx.as_raw_fd(), rx.as_raw_fd(), tx.as_raw_fd()];
+            da.send_with_fds(b"fds", &three).await.unwrap();
+
+            let err = db.recv_with_fds(&mut buf, 1).await.unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        });
+
+        runtime.run().unwrap();
+    }
+
+    #[test]
+    fn test_tcp_socket() {
+        let mut runtime = Runtime::new(256).unwrap();
+
+        let socket = TcpSocket::new_v4().unwrap();
+
+        socket.set_reuseaddr(true).unwrap();
+        socket.set_nodelay(true).unwrap();
+        socket.set_recv_buffer_size(64 * 1024).unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+
+        assert!(socket.reuseaddr().unwrap());
+        assert!(socket.nodelay().unwrap());
+        assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+
+        let addr = socket.local_addr().unwrap();
+        let listener = socket.listen(16).unwrap();
+
+        runtime.spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            stream.write(b"hello").await.unwrap();
+        });
+
+        runtime.spawn(async move {
+            let socket = TcpSocket::new_v4().unwrap();
+
+            socket.set_keepalive(true).unwrap();
+            assert!(socket.keepalive().unwrap());
+
+            let mut stream = socket.connect(addr).await.unwrap();
+
+            let mut buf = [0; 64];
+
+            let len = stream.read(&mut buf).await.unwrap();
+
+            assert_eq!(b"hello", &buf[..len]);
+        });
+
+        runtime.run().unwrap();
+    }
+
+    #[test]
+    fn test_tcp_socket_options() {
+        let socket = TcpSocket::new_v4().unwrap();
+
+        socket.set_tcp_fastopen(5).unwrap();
+        socket.set_tcp_fastopen_connect(true).unwrap();
+
+        assert_eq!(5, socket.tcp_fastopen().unwrap());
+        assert!(socket.tcp_fastopen_connect().unwrap());
+
+        let socket = TcpSocket::new_v6().unwrap();
+
+        socket.set_only_v6(true).unwrap();
+        assert!(socket.only_v6().unwrap());
+
+        socket.set_only_v6(false).unwrap();
+        assert!(!socket.only_v6().unwrap());
+    }
+
+    #[test]
+    fn test_send_file() {
+        use std::io::Read;
+
+        let path = std::env::temp_dir().join(format!("urt-send-file-{}", std::process::id()));
+
+        let data: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = std::thread::spawn(move || {
+            let mut received = Vec::new();
+
+            listener
+                .accept()
+                .unwrap()
+                .0
+                .read_to_end(&mut received)
+                .unwrap();
+
+            received
+        });
+
+        let mut runtime = Runtime::new(256).unwrap();
+
+        let task_path = path.clone();
+
+        runtime.spawn(async move {
+            let file = File::open(&task_path).await.unwrap();
+
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+
+            assert_eq!(
+                200_000,
+                stream.send_file(&file, 1000, 200_000).await.unwrap()
+            );
+
+            // running past the end of the file sends what is there
+            assert_eq!(
+                50_000,
+                stream.send_file(&file, 250_000, 100_000).await.unwrap()
+            );
+        });
+
+        runtime.run().unwrap();
+
+        let received = client.join().unwrap();
+
+        assert_eq!(250_000, received.len());
+        assert!(received[..200_000] == data[1000..201_000]);
+        assert!(received[200_000..] == data[250_000..]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_tcp_connect_errors() {
+        let mut runtime = Runtime::new(256).unwrap();
+
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        runtime.spawn(async move {
+            let _ = listener.accept().await.unwrap();
+            let _ = listener.accept().await.unwrap();
+        });
+
+        runtime.spawn(async move {
+            let err = TcpStream::connect(closed).await.unwrap_err();
+
+            assert_eq!(io::ErrorKind::ConnectionRefused, err.kind());
+
+            // falls through to the address that is listening
+            let stream = TcpStream::connect(&[closed, addr][..]).await.unwrap();
+
+            assert_eq!(addr, stream.peer_addr().unwrap());
+
+            let stream = TcpStream::connect_timeout(addr, Duration::from_secs(5))
+                .await
+                .unwrap();
+
+            assert_eq!(addr, stream.peer_addr().unwrap());
+        });
+
+        runtime.run().unwrap();
+    }
+
+    #[test]
+    fn test_tcp_connect_timeout() {
+        let mut runtime = Runtime::new(256).unwrap();
+
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        let open = listener.local_addr().unwrap();
+
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+
+        let addr = socket.local_addr().unwrap();
+        let _full = socket.listen(0).unwrap();
+
+        // a backlog of 0 holds a single connection, and nothing accepts it, so once it is
+        // established further SYNs are dropped
+        let filler = std::net::TcpStream::connect(addr).unwrap();
+
+        runtime.spawn(async move {
+            let start = Instant::now();
+
+            let err = TcpStream::connect_timeout(addr, Duration::from_millis(200))
+                .await
+                .unwrap_err();
+
+            assert_eq!(io::ErrorKind::TimedOut, err.kind());
+            assert!(start.elapsed() < Duration::from_secs(10));
+
+            // the first attempt hangs for as long as the kernel retries the SYN, so getting through
+            // well before that means the others were tried alongside it
+            let start = Instant::now();
+            let stream = TcpStream::connect(&[addr, closed, open][..]).await.unwrap();
+
+            assert_eq!(open, stream.peer_addr().unwrap());
+            assert!(start.elapsed() < Duration::from_secs(10));
+
+            drop(filler);
+        });
+
+        runtime.spawn(async move {
+            let _ = listener.accept().await.unwrap();
+        });
+
+        runtime.run().unwrap();
+    }
+
+    #[test]
+    fn test_lookup_host() {
+        let mut runtime = Runtime::new(256).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        runtime.spawn(async move {
+            let _ = listener.accept().await.unwrap();
+        });
+
+        runtime.spawn(async move {
+            let addrs: Vec<_> = lookup_host("127.0.0.1:80").await.unwrap().collect();
+
+            assert_eq!(vec!["127.0.0.1:80".parse::<SocketAddr>().unwrap()], addrs);
+
+            let addrs: Vec<_> = lookup_host(("localhost", 80)).await.unwrap().collect();
+
+            assert!(!addrs.is_empty());
+            assert!(addrs
+                .iter()
+                .all(|addr| addr.ip().is_loopback() && addr.port() == 80));
+
+            let stream = TcpStream::connect(format!("localhost:{port}"))
+                .await
+                .unwrap();
+
+            assert_eq!(port, stream.peer_addr().unwrap().port());
+        });
+
+        runtime.run().unwrap();
+    }
+
+    #[test]
+    fn test_lookup_host_stub() {
+        let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut runtime = Runtime::builder()
+            .resolver(move |host, port| match host {
+                "service.test" => Ok(vec![SocketAddr::new(addr.ip(), port)]),
+                _ => Err(io::Error::new(io::ErrorKind::NotFound, "no such host")),
+            })
+            .build()
+            .unwrap();
+
+        runtime.spawn(async move {
+            let _ = listener.accept().await.unwrap();
+        });
+
+        runtime.spawn(async move {
+            let addrs: Vec<_> = lookup_host("service.test:80").await.unwrap().collect();
+
+            assert_eq!(vec![SocketAddr::new(addr.ip(), 80)], addrs);
+
+            let err = lookup_host(("missing.test", 80)).await.err().unwrap();
+
+            assert_eq!(io::ErrorKind::NotFound, err.kind());
+
+            // literal addresses never reach the resolver
+            assert_eq!(1, lookup_host("127.0.0.1:80").await.unwrap().count());
+
+            let stream = TcpStream::connect(("service.test", addr.port()))
+                .await
+                .unwrap();
+
+            assert_eq!(addr, stream.peer_addr().unwrap());
         });
 
         runtime.run().unwrap();
@@ -0,0 +1,178 @@
+//! Anonymous pipes, usable with [`AsyncRead`]/[`AsyncWrite`] or as the kernel-side buffer for
+//! [`splice`](crate::io::splice).
+
+use crate::io::FdIo;
+use futures::io::{AsyncRead, AsyncWrite};
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+/// How many idle pipes a worker keeps around for splicing.
+const PIPE_CACHE_SIZE: usize = 32;
+
+/// The write end of a pipe.
+///
+/// Closing it through [`AsyncWriteExt::close`](futures::AsyncWriteExt::close) or by dropping it
+/// gives the read end EOF.
+#[derive(Debug)]
+pub struct Sender {
+    io: FdIo,
+    // taken once closed
+    fd: Option<OwnedFd>,
+}
+
+/// The read end of a pipe.
+#[derive(Debug)]
+pub struct Receiver {
+    io: FdIo,
+    fd: OwnedFd,
+}
+
+/// Creates a new anonymous pipe, returning its write and read ends.
+pub fn pipe() -> io::Result<(Sender, Receiver)> {
+    let mut fds = [0; 2];
+
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let (rx, tx) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+    Ok((Sender::from(tx), Receiver::from(rx)))
+}
+
+impl From<OwnedFd> for Sender {
+    fn from(fd: OwnedFd) -> Self {
+        Self {
+            io: FdIo::new(fd.as_raw_fd()),
+            fd: Some(fd),
+        }
+    }
+}
+
+impl From<OwnedFd> for Receiver {
+    fn from(fd: OwnedFd) -> Self {
+        Self {
+            io: FdIo::new(fd.as_raw_fd()),
+            fd,
+        }
+    }
+}
+
+impl AsyncWrite for Sender {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.io.poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.io.poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+
+        this.io.poll_close(cx, &mut this.fd)
+    }
+}
+
+impl AsyncRead for Receiver {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.io.poll_read(cx, buf)
+    }
+}
+
+impl AsRawFd for Sender {
+    /// Returns -1 once the sender has been closed.
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_ref().map_or(-1, AsRawFd::as_raw_fd)
+    }
+}
+
+impl AsRawFd for Receiver {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for Receiver {
+    fn into_raw_fd(self) -> RawFd {
+        self.fd.into_raw_fd()
+    }
+}
+
+/// A worker's stash of empty pipes, so splicing through one does not cost two extra syscalls and
+/// a pair of descriptors every time.
+pub(crate) struct PipeCache {
+    pipes: Vec<(Sender, Receiver)>,
+}
+
+impl PipeCache {
+    pub(crate) fn new() -> Self {
+        Self { pipes: Vec::new() }
+    }
+
+    pub(crate) fn take(&mut self) -> io::Result<Rc<(Sender, Receiver)>> {
+        match self.pipes.pop() {
+            Some(pipe) => Ok(Rc::new(pipe)),
+            None => pipe().map(Rc::new),
+        }
+    }
+
+    pub(crate) fn give(&mut self, pipe: Rc<(Sender, Receiver)>) {
+        if self.pipes.len() < PIPE_CACHE_SIZE {
+            if let Ok(pipe) = Rc::try_unwrap(pipe) {
+                self.pipes.push(pipe);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rt::Runtime;
+    use futures::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_pipe_io() {
+        let mut runtime = Runtime::new(256).unwrap();
+
+        runtime.spawn(async {
+            let (mut tx, mut rx) = pipe().unwrap();
+
+            let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+            let expected = data.clone();
+
+            // closing gives the reader EOF while the sender is still around
+            let write = async {
+                tx.write_all(&data).await.unwrap();
+                tx.close().await.unwrap();
+
+                assert_eq!(-1, tx.as_raw_fd());
+
+                let err = tx.write(b"more").await.unwrap_err();
+                assert_eq!(Some(libc::EBADF), err.raw_os_error());
+            };
+
+            let mut received = Vec::new();
+            let read = rx.read_to_end(&mut received);
+
+            let (_, read) = futures::join!(write, read);
+            read.unwrap();
+
+            assert!(received == expected);
+        });
+
+        runtime.run().unwrap();
+    }
+}
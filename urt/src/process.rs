@@ -0,0 +1,363 @@
+//! Spawning child processes and waiting on them without blocking the ring.
+
+use crate::io::poll_ready;
+use crate::pipe::{Receiver, Sender};
+use crate::sys;
+use futures::future;
+use futures::io::AsyncReadExt;
+use std::ffi::OsStr;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::Path;
+use std::process::{ExitStatus, Output, Stdio};
+use std::{io, mem};
+
+/// A process builder, wrapping [`std::process::Command`].
+///
+/// Piped stdio is handed back as [`pipe`](crate::pipe) handles, so it can be read and written
+/// asynchronously.
+#[derive(Debug)]
+pub struct Command {
+    inner: std::process::Command,
+    // which streams the caller configured, as std has no getters for them
+    stdin_set: bool,
+    stdout_set: bool,
+    stderr_set: bool,
+}
+
+/// A spawned child process.
+#[derive(Debug)]
+pub struct Child {
+    inner: std::process::Child,
+    pidfd: Option<OwnedFd>,
+    status: Option<ExitStatus>,
+    pub stdin: Option<Sender>,
+    pub stdout: Option<Receiver>,
+    pub stderr: Option<Receiver>,
+}
+
+impl Command {
+    pub fn new<S: AsRef<OsStr>>(program: S) -> Self {
+        Self {
+            inner: std::process::Command::new(program),
+            stdin_set: false,
+            stdout_set: false,
+            stderr_set: false,
+        }
+    }
+
+    pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Self {
+        self.inner.arg(arg);
+        self
+    }
+
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.inner.args(args);
+        self
+    }
+
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(&mut self, key: K, val: V) -> &mut Self {
+        self.inner.env(key, val);
+        self
+    }
+
+    pub fn envs<I, K, V>(&mut self, vars: I) -> &mut Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        self.inner.envs(vars);
+        self
+    }
+
+    pub fn env_remove<K: AsRef<OsStr>>(&mut self, key: K) -> &mut Self {
+        self.inner.env_remove(key);
+        self
+    }
+
+    pub fn env_clear(&mut self) -> &mut Self {
+        self.inner.env_clear();
+        self
+    }
+
+    pub fn current_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.inner.current_dir(dir);
+        self
+    }
+
+    pub fn stdin<T: Into<Stdio>>(&mut self, cfg: T) -> &mut Self {
+        self.inner.stdin(cfg);
+        self.stdin_set = true;
+        self
+    }
+
+    pub fn stdout<T: Into<Stdio>>(&mut self, cfg: T) -> &mut Self {
+        self.inner.stdout(cfg);
+        self.stdout_set = true;
+        self
+    }
+
+    pub fn stderr<T: Into<Stdio>>(&mut self, cfg: T) -> &mut Self {
+        self.inner.stderr(cfg);
+        self.stderr_set = true;
+        self
+    }
+
+    /// Starts the process. Forking itself is synchronous, as with std.
+    pub fn spawn(&mut self) -> io::Result<Child> {
+        let mut inner = self.inner.spawn()?;
+
+        let pidfd = match pidfd_open(inner.id()) {
+            Ok(pidfd) => Some(pidfd),
+            Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => None,
+            Err(e) => {
+                let _ = inner.kill();
+                let _ = inner.wait();
+
+                return Err(e);
+            }
+        };
+
+        let stdin = inner.stdin.take().map(|s| Sender::from(OwnedFd::from(s)));
+        let stdout = inner
+            .stdout
+            .take()
+            .map(|s| Receiver::from(OwnedFd::from(s)));
+        let stderr = inner
+            .stderr
+            .take()
+            .map(|s| Receiver::from(OwnedFd::from(s)));
+
+        Ok(Child {
+            inner,
+            pidfd,
+            status: None,
+            stdin,
+            stdout,
+            stderr,
+        })
+    }
+
+    /// Runs the process to completion, inheriting stdio unless configured otherwise.
+    pub async fn status(&mut self) -> io::Result<ExitStatus> {
+        self.spawn()?.wait().await
+    }
+
+    /// Runs the process to completion, collecting its stdout and stderr.
+    ///
+    /// As with std, streams not configured on the builder default to a null stdin and piped stdout
+    /// and stderr, for this call only.
+    pub async fn output(&mut self) -> io::Result<Output> {
+        if !self.stdin_set {
+            self.inner.stdin(Stdio::null());
+        }
+
+        if !self.stdout_set {
+            self.inner.stdout(Stdio::piped());
+        }
+
+        if !self.stderr_set {
+            self.inner.stderr(Stdio::piped());
+        }
+
+        let child = self.spawn();
+
+        // back to inheriting, which is what spawn and status do with nothing configured
+        if !self.stdin_set {
+            self.inner.stdin(Stdio::inherit());
+        }
+
+        if !self.stdout_set {
+            self.inner.stdout(Stdio::inherit());
+        }
+
+        if !self.stderr_set {
+            self.inner.stderr(Stdio::inherit());
+        }
+
+        let mut child = child?;
+
+        drop(child.stdin.take());
+
+        child.wait_with_output().await
+    }
+}
+
+impl Child {
+    pub fn id(&self) -> u32 {
+        self.inner.id()
+    }
+
+    /// Sends `SIGKILL` to the process, unless it has already been waited on.
+    pub fn kill(&mut self) -> io::Result<()> {
+        self.inner.kill()
+    }
+
+    /// Returns the exit status if the process has exited, without waiting.
+    pub fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        if let Some(status) = self.status {
+            return Ok(Some(status));
+        }
+
+        self.status = self.inner.try_wait()?;
+
+        Ok(self.status)
+    }
+
+    /// Waits for the process to exit. Stdin is closed first, so a child reading it can finish.
+    ///
+    /// The io-uring version we build against has no `WAITID` op, so this polls a pidfd for the
+    /// exit instead. Kernels without pidfds (before 5.3) wait on the blocking pool.
+    pub async fn wait(&mut self) -> io::Result<ExitStatus> {
+        drop(self.stdin.take());
+
+        if let Some(status) = self.status {
+            return Ok(status);
+        }
+
+        match &self.pidfd {
+            Some(pidfd) => poll_ready(pidfd.as_raw_fd(), libc::POLLIN).await?,
+            None => {
+                let pid = self.inner.id();
+
+                // WNOWAIT leaves the zombie for `wait` below to reap
+                sys::run_blocking(move || {
+                    let mut info = unsafe { mem::zeroed::<libc::siginfo_t>() };
+
+                    loop {
+                        let flags = libc::WEXITED | libc::WNOWAIT;
+
+                        if unsafe { libc::waitid(libc::P_PID, pid, &mut info, flags) } == 0 {
+                            return Ok(());
+                        }
+
+                        let err = io::Error::last_os_error();
+
+                        if err.kind() != io::ErrorKind::Interrupted {
+                            return Err(err);
+                        }
+                    }
+                })
+                .await?
+            }
+        }
+
+        // the process has exited, so this only reaps it
+        let status = self.inner.wait()?;
+
+        self.status = Some(status);
+
+        Ok(status)
+    }
+
+    /// Waits for the process to exit while collecting whatever is piped from its stdout and
+    /// stderr.
+    pub async fn wait_with_output(mut self) -> io::Result<Output> {
+        drop(self.stdin.take());
+
+        async fn read_all(pipe: Option<Receiver>) -> io::Result<Vec<u8>> {
+            let mut buf = Vec::new();
+
+            if let Some(mut pipe) = pipe {
+                pipe.read_to_end(&mut buf).await?;
+            }
+
+            Ok(buf)
+        }
+
+        let (stdout, stderr) =
+            future::try_join(read_all(self.stdout.take()), read_all(self.stderr.take())).await?;
+
+        let status = self.wait().await?;
+
+        Ok(Output {
+            status,
+            stdout,
+            stderr,
+        })
+    }
+}
+
+fn pidfd_open(pid: u32) -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as _) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rt::Runtime;
+    use futures::AsyncWriteExt;
+
+    #[test]
+    fn test_process() {
+        let mut runtime = Runtime::new(256).unwrap();
+
+        runtime.spawn(async {
+            let output = Command::new("sh")
+                .args(["-c", "echo out; echo err >&2; exit 3"])
+                .output()
+                .await
+                .unwrap();
+
+            assert_eq!(Some(3), output.status.code());
+            assert_eq!(b"out\n", &output.stdout[..]);
+            assert_eq!(b"err\n", &output.stderr[..]);
+
+            // stdin is null rather than inherited, so cat sees EOF straight away
+            let mut command = Command::new("sh");
+            command.args(["-c", "cat; echo done >&2"]);
+
+            let output = command.output().await.unwrap();
+
+            assert!(output.stdout.is_empty());
+            assert_eq!(b"done\n", &output.stderr[..]);
+
+            // the defaults only applied to that call
+            let mut child = command
+                .stdin(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap();
+
+            assert!(child.stdout.is_none());
+            assert!(child.wait().await.unwrap().success());
+
+            let mut child = Command::new("cat")
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap();
+
+            let mut stdin = child.stdin.take().unwrap();
+            stdin.write_all(b"hello").await.unwrap();
+            drop(stdin);
+
+            let output = child.wait_with_output().await.unwrap();
+
+            assert!(output.status.success());
+            assert_eq!(b"hello", &output.stdout[..]);
+
+            let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+
+            assert!(child.try_wait().unwrap().is_none());
+
+            child.kill().unwrap();
+
+            let status = child.wait().await.unwrap();
+            assert!(!status.success());
+            assert_eq!(Some(status), child.try_wait().unwrap());
+        });
+
+        runtime.run().unwrap();
+    }
+}
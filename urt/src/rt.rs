@@ -1,19 +1,376 @@
-use crate::sys::{Driver, Task, ThreadContext, Worker, CONTEXT};
+use crate::net::Resolver;
+use crate::sys::{
+    self, BlockingPool, Driver, Metrics, Notifier, PollPolicy, Remote, Task, ThreadContext, Worker,
+    CONTEXT,
+};
 use crate::task::JoinHandle;
+use futures::FutureExt;
 use std::cell::RefCell;
 use std::future::Future;
-use std::io;
+use std::panic::AssertUnwindSafe;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{fmt, io, mem};
+use tokio::sync::oneshot;
+
+const DEFAULT_ENTRIES: u32 = 256;
+const DEFAULT_MAX_BLOCKING_THREADS: usize = 512;
+const DEFAULT_BLOCKING_KEEP_ALIVE: Duration = Duration::from_secs(10);
+const DEFAULT_EVENT_INTERVAL: u32 = 128;
+
+/// How long dropping a [`Runtime`] waits for the kernel to release cancelled ops.
+const DROP_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub struct Runtime {
     worker: Worker,
+    shut_down: bool,
+}
+
+/// Stops a [`Runtime`] from any thread: [`Runtime::run`] returns at its next tick, leaving the
+/// remaining tasks for [`Runtime::shutdown`] or drop to clean up.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    stop: Arc<AtomicBool>,
+    notifier: Arc<Notifier>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.stop.store(true, Ordering::Release);
+
+        // a parked ring only notices once something completes
+        self.notifier.wake();
+    }
+}
+
+/// Spawns tasks onto a [`Runtime`] from any thread.
+///
+/// Tasks spawned from another thread are queued for the runtime and wake it if it is parked. They
+/// run once [`Runtime::run`] is running, which returns as soon as it has no tasks left, so they
+/// should be spawned before it starts or while other tasks keep it going.
+#[derive(Clone)]
+pub struct Handle {
+    remote: Arc<Remote>,
+}
+
+impl Handle {
+    /// Returns a handle to the runtime running the current task.
+    ///
+    /// # Panics
+    /// Panics outside of a runtime, see [`try_current`](Self::try_current).
+    pub fn current() -> Self {
+        match Self::try_current() {
+            Ok(handle) => handle,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    /// Returns a handle to the runtime running the current task, or an error outside of one.
+    pub fn try_current() -> Result<Self, TryCurrentError> {
+        CONTEXT
+            .try_with(|maybe| {
+                maybe.borrow().as_ref().map(|context| Handle {
+                    remote: context.remote.clone(),
+                })
+            })
+            .ok()
+            .flatten()
+            .ok_or(TryCurrentError { _private: () })
+    }
+
+    /// Spawns `fut` onto the runtime.
+    ///
+    /// On the runtime's own thread this is the same as [`spawn`](crate::spawn). From any other
+    /// thread, `fut` is sent over to the runtime's spawn queue, and the returned handle may be
+    /// awaited by any executor on the calling thread; like every [`JoinHandle`] it is not `Send`.
+    /// A panic in `fut` is resumed in whichever task awaits the handle. If the runtime has shut
+    /// down, `fut` is dropped and awaiting the handle panics.
+    pub fn spawn<T, F>(&self, fut: F) -> JoinHandle<T>
+    where
+        F: Future<Output = T> + Send + 'static,
+        T: Send + 'static,
+    {
+        if sys::with_worker_context(&self.remote, |_| ()).is_some() {
+            let (task, handle) = Task::new(fut);
+
+            sys::spawn_task(task);
+
+            return handle;
+        }
+
+        let (tx, rx) = oneshot::channel();
+
+        self.remote.spawn(Box::pin(async move {
+            // caught here rather than unwinding through the runtime
+            let _ = tx.send(AssertUnwindSafe(fut).catch_unwind().await);
+        }));
+
+        JoinHandle::remote(rx)
+    }
+}
+
+impl fmt::Debug for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Handle").finish_non_exhaustive()
+    }
+}
+
+/// Returned by [`Handle::try_current`] on a thread with no runtime running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TryCurrentError {
+    _private: (),
+}
+
+impl fmt::Display for TryCurrentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(sys::NO_CONTEXT)
+    }
+}
+
+impl std::error::Error for TryCurrentError {}
+
+/// A snapshot of a [`Runtime`]'s counters, from [`Runtime::metrics`].
+///
+/// Counters start at zero when the runtime is built and only go up. Keeping them costs a few
+/// non-atomic increments per task poll and ring syscall, so they are always on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RuntimeMetrics {
+    pub(crate) tasks_spawned: u64,
+    pub(crate) tasks_completed: u64,
+    pub(crate) polls: u64,
+    pub(crate) parks: u64,
+    pub(crate) park_time: Duration,
+    pub(crate) spins: u64,
+    pub(crate) spin_time: Duration,
+    pub(crate) sqes_submitted: u64,
+    pub(crate) cqes_reaped: u64,
+    pub(crate) submit_calls: u64,
+    pub(crate) sq_full_retries: u64,
+    pub(crate) cancelled_ops: u64,
+    pub(crate) op_slots: usize,
+    pub(crate) op_slot_capacity: usize,
+}
+
+impl RuntimeMetrics {
+    /// Tasks the worker has taken in, from any thread.
+    pub fn tasks_spawned(&self) -> u64 {
+        self.tasks_spawned
+    }
+
+    /// Tasks that ran to completion. Tasks dropped at shutdown are not counted.
+    pub fn tasks_completed(&self) -> u64 {
+        self.tasks_completed
+    }
+
+    /// Times a task was polled.
+    pub fn polls(&self) -> u64 {
+        self.polls
+    }
+
+    /// Times the worker blocked in the kernel waiting for a completion.
+    pub fn parks(&self) -> u64 {
+        self.parks
+    }
+
+    /// Total time spent parked.
+    pub fn park_time(&self) -> Duration {
+        self.park_time
+    }
+
+    /// Times the worker spun on the completion queue while idle, under
+    /// [`Builder::busy_poll`] or [`Builder::spin_before_park`].
+    pub fn spins(&self) -> u64 {
+        self.spins
+    }
+
+    /// Total time spent spinning, including spins that gave up and parked.
+    pub fn spin_time(&self) -> Duration {
+        self.spin_time
+    }
+
+    /// Submission queue entries the kernel consumed.
+    pub fn sqes_submitted(&self) -> u64 {
+        self.sqes_submitted
+    }
+
+    /// Completion queue entries processed, including the runtime's internal ones.
+    pub fn cqes_reaped(&self) -> u64 {
+        self.cqes_reaped
+    }
+
+    /// `io_uring_enter` calls made to submit or wait.
+    pub fn submit_calls(&self) -> u64 {
+        self.submit_calls
+    }
+
+    /// Times an op found the submission queue full and had to submit to make room.
+    pub fn sq_full_retries(&self) -> u64 {
+        self.sq_full_retries
+    }
+
+    /// Ops whose future was dropped before they completed, counted as their completion arrives.
+    pub fn cancelled_ops(&self) -> u64 {
+        self.cancelled_ops
+    }
+
+    /// Ops currently tracked by the driver, whether in flight or completed but not yet picked up.
+    pub fn op_slots(&self) -> usize {
+        self.op_slots
+    }
+
+    /// How many ops the driver can track before its slab grows.
+    pub fn op_slot_capacity(&self) -> usize {
+        self.op_slot_capacity
+    }
+}
+
+/// Configures and builds a [`Runtime`].
+#[derive(Clone)]
+pub struct Builder {
+    entries: u32,
+    max_blocking_threads: usize,
+    blocking_keep_alive: Duration,
+    event_interval: u32,
+    adaptive_polling: bool,
+    busy_poll: bool,
+    spin_before_park: Option<Duration>,
+    resolver: Option<Resolver>,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self {
+            entries: DEFAULT_ENTRIES,
+            max_blocking_threads: DEFAULT_MAX_BLOCKING_THREADS,
+            blocking_keep_alive: DEFAULT_BLOCKING_KEEP_ALIVE,
+            event_interval: DEFAULT_EVENT_INTERVAL,
+            adaptive_polling: false,
+            busy_poll: false,
+            spin_before_park: None,
+            resolver: None,
+        }
+    }
+
+    /// Sets the size of the submission queue.
+    pub fn entries(&mut self, entries: u32) -> &mut Self {
+        self.entries = entries;
+        self
+    }
+
+    /// Caps the number of threads the blocking pool may grow to. Threads are only started when
+    /// work is queued and none are idle.
+    ///
+    /// # Panics
+    /// [`build`](Self::build) panics if this is zero.
+    pub fn max_blocking_threads(&mut self, max: usize) -> &mut Self {
+        self.max_blocking_threads = max;
+        self
+    }
+
+    /// Sets how long an idle blocking thread waits for more work before exiting.
+    pub fn blocking_keep_alive(&mut self, keep_alive: Duration) -> &mut Self {
+        self.blocking_keep_alive = keep_alive;
+        self
+    }
+
+    /// Sets how many tasks are polled between reaping completions from the ring, 128 by default.
+    /// Lower values get completions to their tasks sooner, at the cost of more syscalls.
+    ///
+    /// # Panics
+    /// [`build`](Self::build) panics if this is zero.
+    pub fn event_interval(&mut self, interval: u32) -> &mut Self {
+        self.event_interval = interval;
+        self
+    }
+
+    /// Also submits and reaps completions as soon as the submission queue is half full, rather
+    /// than only every [`event_interval`](Self::event_interval) polls or once it overflows, so
+    /// bursts of ops reach the kernel without waiting on the rest of the tick.
+    pub fn adaptive_polling(&mut self, enabled: bool) -> &mut Self {
+        self.adaptive_polling = enabled;
+        self
+    }
+
+    /// Never blocks in the kernel while idle, spinning on the completion queue instead. This cuts
+    /// wake-up latency at the cost of keeping a core busy.
+    pub fn busy_poll(&mut self, enabled: bool) -> &mut Self {
+        self.busy_poll = enabled;
+        self
+    }
+
+    /// Spins on the completion queue for up to `spin` when idle before blocking in the kernel,
+    /// catching completions that arrive shortly without a syscall and a thread wake-up.
+    pub fn spin_before_park(&mut self, spin: Duration) -> &mut Self {
+        self.spin_before_park = Some(spin);
+        self
+    }
+
+    /// Resolves host names with `resolve` instead of the system resolver, so tests can serve names
+    /// from a stub. It runs on the blocking pool like the system resolver does.
+    #[cfg(test)]
+    pub(crate) fn resolver<F>(&mut self, resolve: F) -> &mut Self
+    where
+        F: Fn(&str, u16) -> io::Result<Vec<std::net::SocketAddr>> + Send + Sync + 'static,
+    {
+        self.resolver = Some(Arc::new(resolve));
+        self
+    }
+
+    pub fn build(&self) -> io::Result<Runtime> {
+        assert!(
+            self.event_interval > 0,
+            "Event interval needs to be at least one poll"
+        );
+
+        let policy = PollPolicy {
+            interval: self.event_interval,
+            adaptive: self.adaptive_polling,
+            busy_poll: self.busy_poll,
+            spin_before_park: self.spin_before_park.filter(|spin| !spin.is_zero()),
+        };
+
+        let metrics = Rc::new(Metrics::default());
+
+        let driver = Rc::new(RefCell::new(Driver::new(self.entries, metrics.clone())?));
+        let blocking = BlockingPool::new(self.max_blocking_threads, self.blocking_keep_alive);
+        let worker = Worker::new(driver, blocking, policy, metrics, self.resolver.clone());
+        Ok(Runtime {
+            worker,
+            shut_down: false,
+        })
+    }
+}
+
+impl fmt::Debug for Builder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Builder")
+            .field("entries", &self.entries)
+            .field("max_blocking_threads", &self.max_blocking_threads)
+            .field("blocking_keep_alive", &self.blocking_keep_alive)
+            .field("event_interval", &self.event_interval)
+            .field("adaptive_polling", &self.adaptive_polling)
+            .field("busy_poll", &self.busy_poll)
+            .field("spin_before_park", &self.spin_before_park)
+            .field("resolver", &self.resolver.is_some())
+            .finish()
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Runtime {
     pub fn new(entries: u32) -> io::Result<Self> {
-        let driver = Rc::new(RefCell::new(Driver::new(entries)?));
-        let worker = Worker::new(driver);
-        Ok(Self { worker })
+        Builder::new().entries(entries).build()
+    }
+
+    pub fn builder() -> Builder {
+        Builder::new()
     }
 
     pub fn spawn<T, F>(&self, fut: F) -> JoinHandle<T>
@@ -28,31 +385,406 @@ impl Runtime {
         handle
     }
 
-    pub fn run(&mut self) -> io::Result<()> {
-        // exit context when this finishes
-        struct ContextGuard {}
+    /// Returns a handle for spawning onto this runtime, including from other threads.
+    pub fn handle(&self) -> Handle {
+        Handle {
+            remote: self.worker.remote(),
+        }
+    }
 
-        impl Drop for ContextGuard {
-            fn drop(&mut self) {
-                CONTEXT.with(|x| {
-                    let mut guard = x.borrow_mut();
+    /// Returns a snapshot of the runtime's counters.
+    pub fn metrics(&self) -> RuntimeMetrics {
+        self.worker.driver().borrow().metrics()
+    }
 
-                    *guard = None;
-                });
-            }
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            stop: self.worker.stop_flag(),
+            notifier: self.worker.driver().borrow().notifier(),
         }
+    }
+
+    /// Runs tasks until they have all finished, or until [`ShutdownHandle::shutdown`] is called.
+    pub fn run(&mut self) -> io::Result<()> {
+        let _guard = self.enter();
 
-        // enter context
-        CONTEXT.with(|x| {
+        self.worker.run()
+    }
+
+    /// Shuts the runtime down: no more tasks are accepted, every op still in flight is cancelled
+    /// and, once the kernel has released them all, the remaining tasks are dropped.
+    ///
+    /// If the kernel still holds ops after `timeout`, the memory they may write to is leaked
+    /// rather than freed, along with the tasks, and `TimedOut` is returned. Dropping a runtime
+    /// does the same with a timeout of a few seconds.
+    pub fn shutdown(mut self, timeout: Duration) -> io::Result<()> {
+        self.shutdown_inner(timeout)
+    }
+
+    fn shutdown_inner(&mut self, timeout: Duration) -> io::Result<()> {
+        self.shut_down = true;
+
+        let deadline = Instant::now() + timeout;
+
+        let _guard = self.enter();
+
+        self.worker.shutdown(deadline)
+    }
+
+    fn enter(&self) -> ContextGuard {
+        // enter context, keeping whichever runtime's context this one is entered from
+        let prev = CONTEXT.with(|x| {
             let mut guard = x.borrow_mut();
 
-            *guard = Some(ThreadContext {
+            guard.replace(ThreadContext {
                 spawner: self.worker.spawner(),
                 driver: self.worker.driver(),
                 scheduler: self.worker.scheduler(),
+                blocking: self.worker.blocking(),
+                pipes: self.worker.pipes(),
+                signals: self.worker.signals(),
+                remote: self.worker.remote(),
+                resolver: self.worker.resolver(),
+            })
+        });
+
+        ContextGuard { prev }
+    }
+}
+
+impl Drop for Runtime {
+    fn drop(&mut self) {
+        if !self.shut_down {
+            let _ = self.shutdown_inner(DROP_SHUTDOWN_TIMEOUT);
+        }
+    }
+}
+
+/// Exits the runtime's context when dropped, restoring the one it was entered from, e.g. when a
+/// runtime is dropped inside a task of another.
+struct ContextGuard {
+    prev: Option<ThreadContext>,
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        let context = CONTEXT.with(|x| mem::replace(&mut *x.borrow_mut(), self.prev.take()));
+
+        // dropped outside the borrow, as the last references to a runtime's parts may go with it
+        mem::drop(context);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::TcpListener;
+    use std::cell::Cell;
+    use std::{panic, thread};
+
+    #[test]
+    fn test_shutdown() {
+        struct SetOnDrop(Rc<Cell<bool>>);
+
+        impl Drop for SetOnDrop {
+            fn drop(&mut self) {
+                self.0.set(true);
+
+                // spawning while shutting down is quietly refused
+                crate::spawn(async {});
+            }
+        }
+
+        let mut runtime = Runtime::new(256).unwrap();
+
+        let dropped = Rc::new(Cell::new(false));
+        let guard = SetOnDrop(dropped.clone());
+
+        // a server loop which never finishes on its own, parked on an accept op
+        runtime.spawn(async move {
+            let _guard = guard;
+
+            let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap(), false).unwrap();
+
+            loop {
+                let _ = listener.accept().await;
+            }
+        });
+
+        let handle = runtime.shutdown_handle();
+
+        let stopper = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+
+            handle.shutdown();
+        });
+
+        runtime.run().unwrap();
+        stopper.join().unwrap();
+
+        assert!(!dropped.get());
+
+        runtime.shutdown(Duration::from_secs(5)).unwrap();
+
+        assert!(dropped.get());
+    }
+
+    #[test]
+    fn test_drop_nested() {
+        let mut runtime = Runtime::new(256).unwrap();
+
+        runtime.spawn(async {
+            let inner = Runtime::new(256).unwrap();
+
+            inner.spawn(async {});
+
+            // shutting down enters the inner runtime's context
+            drop(inner);
+
+            // and leaves this one's in place afterwards
+            crate::spawn(async {}).await;
+            crate::time::sleep(Duration::from_millis(1)).await.unwrap();
+        });
+
+        runtime.run().unwrap();
+    }
+
+    #[test]
+    fn test_polling_policies() {
+        fn run(configure: impl FnOnce(&mut Builder) -> &mut Builder) -> RuntimeMetrics {
+            let mut runtime = configure(Runtime::builder().entries(8)).build().unwrap();
+
+            runtime.spawn(async {
+                // many more ops than the submission queue holds
+                let sleeps = (0..64).map(|_| crate::time::sleep(Duration::ZERO));
+
+                for res in futures::future::join_all(sleeps).await {
+                    res.unwrap();
+                }
+
+                // each one a tick of its own
+                for _ in 0..256 {
+                    crate::task::yield_now().await;
+                }
+
+                // idle for much longer than the spin below, so it gives up and parks
+                crate::time::sleep(Duration::from_millis(20)).await.unwrap();
+
+                // completes through the notifier rather than an op
+                assert_eq!(7, crate::task::spawn_blocking(|| 7).await);
+            });
+
+            runtime.run().unwrap();
+
+            runtime.metrics()
+        }
+
+        let default = run(|b| b);
+        assert!(default.parks() > 0);
+        assert_eq!(0, default.spins());
+
+        // reaps after every task instead of every 128
+        let eager = run(|b| b.event_interval(1).adaptive_polling(true));
+        assert!(eager.submit_calls() >= default.submit_calls() + 128);
+
+        let spin = run(|b| b.spin_before_park(Duration::from_millis(1)));
+        assert!(spin.spins() > 0);
+        assert!(spin.parks() > 0);
+
+        let busy = run(|b| b.busy_poll(true));
+        assert!(busy.spins() > 0);
+        assert_eq!(0, busy.parks());
+    }
+
+    #[test]
+    fn test_handle() {
+        let err = Handle::try_current().unwrap_err();
+        assert!(err.to_string().contains("no urt runtime"));
+
+        let mut runtime = Runtime::new(256).unwrap();
+        let handle = runtime.handle();
+
+        let (done_tx, done_rx) = oneshot::channel();
+
+        // keeps the runtime going until the remote task has run
+        runtime.spawn(async move {
+            done_rx.await.unwrap();
+
+            let local = Handle::current().spawn(async { thread::current().id() });
+            assert_eq!(thread::current().id(), local.await);
+        });
+
+        let spawner = thread::spawn(move || {
+            // by now the ring is parked, so the spawn has to wake it
+            thread::sleep(Duration::from_millis(20));
+
+            // the panic is carried over to the handle, leaving the runtime running
+            let failed = handle.spawn(async { panic!("remote task failed") });
+
+            let payload = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                futures::executor::block_on(failed)
+            }))
+            .unwrap_err();
+
+            assert_eq!(Some(&"remote task failed"), payload.downcast_ref::<&str>());
+
+            let remote = handle.spawn(async move {
+                done_tx.send(()).unwrap();
+
+                thread::current().id()
             });
+
+            futures::executor::block_on(remote)
         });
 
-        self.worker.run()
+        runtime.run().unwrap();
+
+        assert_eq!(thread::current().id(), spawner.join().unwrap());
+
+        let handle = runtime.handle();
+        runtime.shutdown(Duration::from_secs(5)).unwrap();
+
+        // refused once shut down
+        let dropped = Arc::new(AtomicBool::new(false));
+        let flag = dropped.clone();
+
+        let rejected = handle.spawn(async move {
+            flag.store(true, Ordering::SeqCst);
+        });
+
+        let res = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            futures::executor::block_on(rejected)
+        }));
+
+        assert!(res.is_err());
+        assert!(!dropped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_wake_from_other_threads() {
+        use std::sync::mpsc;
+        use std::task::Poll;
+
+        let mut runtime = Runtime::new(256).unwrap();
+
+        runtime.spawn(async {
+            let (waker_tx, waker_rx) = mpsc::channel();
+            let (done_tx, done_rx) = mpsc::channel();
+            let woken = Arc::new(AtomicBool::new(false));
+
+            let flag = woken.clone();
+            let waker_thread = thread::spawn(move || {
+                let waker: std::task::Waker = waker_rx.recv().unwrap();
+
+                flag.store(true, Ordering::SeqCst);
+                waker.wake_by_ref();
+
+                // kept until the task has finished, so this drops the last reference to it
+                done_rx.recv().unwrap();
+                mem::drop(waker);
+            });
+
+            let mut waker_tx = Some(waker_tx);
+
+            crate::spawn(futures::future::poll_fn(move |cx| {
+                if woken.load(Ordering::SeqCst) {
+                    return Poll::Ready(());
+                }
+
+                if let Some(tx) = waker_tx.take() {
+                    tx.send(cx.waker().clone()).unwrap();
+                }
+
+                Poll::Pending
+            }))
+            .await;
+
+            done_tx.send(()).unwrap();
+            waker_thread.join().unwrap();
+        });
+
+        runtime.run().unwrap();
+
+        // a task on this runtime awaits one on another, whose thread then wakes it
+        let (handle_tx, handle_rx) = mpsc::channel();
+        let (done_tx, done_rx) = oneshot::channel::<()>();
+
+        let other = thread::spawn(move || {
+            let mut runtime = Runtime::new(256).unwrap();
+
+            handle_tx.send(runtime.handle()).unwrap();
+
+            runtime.spawn(async move {
+                done_rx.await.unwrap();
+            });
+
+            runtime.run().unwrap();
+        });
+
+        let handle = handle_rx.recv().unwrap();
+
+        runtime.spawn(async move {
+            let id = handle
+                .spawn(async {
+                    // gives the task awaiting this time to park
+                    thread::sleep(Duration::from_millis(10));
+
+                    thread::current().id()
+                })
+                .await;
+
+            assert_ne!(thread::current().id(), id);
+
+            done_tx.send(()).unwrap();
+        });
+
+        runtime.run().unwrap();
+        other.join().unwrap();
+    }
+
+    #[test]
+    fn test_metrics() {
+        use crate::time::sleep;
+        use futures::future::{self, Either};
+
+        let mut runtime = Runtime::new(4).unwrap();
+
+        assert_eq!(RuntimeMetrics::default().polls(), runtime.metrics().polls());
+
+        runtime.spawn(async {
+            let children: Vec<_> = (0..3).map(|_| crate::spawn(async {})).collect();
+
+            future::join_all(children).await;
+
+            // more ops at once than the submission queue holds
+            for res in future::join_all((0..16).map(|_| sleep(Duration::ZERO))).await {
+                res.unwrap();
+            }
+
+            let short = Box::pin(sleep(Duration::from_millis(1)));
+            let long = Box::pin(sleep(Duration::from_millis(10)));
+
+            // the long sleep is dropped while in flight, and completes during this one
+            assert!(matches!(future::select(short, long).await, Either::Left(_)));
+
+            sleep(Duration::from_millis(30)).await.unwrap();
+        });
+
+        runtime.run().unwrap();
+
+        let metrics = runtime.metrics();
+
+        assert_eq!(4, metrics.tasks_spawned());
+        assert_eq!(4, metrics.tasks_completed());
+        assert!(metrics.polls() >= 4);
+        assert!(metrics.parks() >= 1);
+        assert!(metrics.park_time() >= Duration::from_millis(20));
+        assert!(metrics.sqes_submitted() >= 19);
+        assert!(metrics.cqes_reaped() >= 19);
+        assert!(metrics.submit_calls() >= 4);
+        assert!(metrics.sq_full_retries() >= 1);
+        assert_eq!(1, metrics.cancelled_ops());
+        assert_eq!(0, metrics.op_slots());
+        assert!(metrics.op_slot_capacity() > 0);
     }
 }
@@ -0,0 +1,301 @@
+//! Unix signals delivered as async events, through a `signalfd` read on the ring.
+
+use crate::submit_op;
+use crate::sys::{self, Op};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+use std::{fmt, io, mem};
+
+/// How many signals a single read of the signalfd can pick up.
+const SIGINFO_BATCH: usize = 8;
+
+type SigInfoBuf = Box<[libc::signalfd_siginfo; SIGINFO_BATCH]>;
+
+/// A signal that can be listened for with [`signal`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SignalKind(libc::c_int);
+
+impl SignalKind {
+    pub const fn from_raw(signum: libc::c_int) -> Self {
+        Self(signum)
+    }
+
+    pub const fn as_raw_value(&self) -> libc::c_int {
+        self.0
+    }
+
+    /// `SIGINT`, sent by the terminal on ctrl-c.
+    pub const fn interrupt() -> Self {
+        Self(libc::SIGINT)
+    }
+
+    /// `SIGTERM`, the conventional request to shut down.
+    pub const fn terminate() -> Self {
+        Self(libc::SIGTERM)
+    }
+
+    pub const fn hangup() -> Self {
+        Self(libc::SIGHUP)
+    }
+
+    pub const fn quit() -> Self {
+        Self(libc::SIGQUIT)
+    }
+
+    pub const fn user_defined1() -> Self {
+        Self(libc::SIGUSR1)
+    }
+
+    pub const fn user_defined2() -> Self {
+        Self(libc::SIGUSR2)
+    }
+
+    pub const fn child() -> Self {
+        Self(libc::SIGCHLD)
+    }
+
+    pub const fn window_change() -> Self {
+        Self(libc::SIGWINCH)
+    }
+}
+
+/// Subscribes to `kind` on the current runtime.
+///
+/// The signal is blocked on the runtime's thread and read from a signalfd shared by every
+/// subscriber on the runtime, each of which sees every delivery. Since a process-directed signal
+/// goes to any thread that does not block it, other threads should block it as well. The
+/// runtime's blocking pool threads block every signal. The signal stays blocked once subscribers
+/// are gone.
+///
+/// Deliveries arriving while a subscriber is not waiting are coalesced into one.
+pub fn signal(kind: SignalKind) -> io::Result<Signal> {
+    let registry = sys::with_context(|context| context.signals.clone());
+
+    let seen = registry.borrow_mut().register(kind)?;
+
+    Ok(Signal {
+        registry,
+        kind,
+        seen,
+    })
+}
+
+/// Waits for the next ctrl-c, i.e. `SIGINT`.
+pub async fn ctrl_c() -> io::Result<()> {
+    signal(SignalKind::interrupt())?.recv().await;
+
+    Ok(())
+}
+
+/// A subscription to one signal, created by [`signal`].
+pub struct Signal {
+    registry: Rc<RefCell<SignalRegistry>>,
+    kind: SignalKind,
+    seen: u64,
+}
+
+impl Signal {
+    /// Waits for the next delivery of the signal.
+    ///
+    /// Returns `None` if reading the signalfd failed, after which no more signals arrive.
+    pub async fn recv(&mut self) -> Option<()> {
+        std::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<()>> {
+        let mut registry = self.registry.borrow_mut();
+
+        registry.poll_read(cx);
+
+        if registry.failed {
+            return Poll::Ready(None);
+        }
+
+        let slot = registry.slots.get_mut(&self.kind.0).unwrap();
+
+        if slot.received > self.seen {
+            self.seen = slot.received;
+
+            return Poll::Ready(Some(()));
+        }
+
+        if !slot.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+            slot.wakers.push(cx.waker().clone());
+        }
+
+        Poll::Pending
+    }
+}
+
+impl Drop for Signal {
+    fn drop(&mut self) {
+        // this task may be the one the pending read wakes, so let another subscriber take over
+        self.registry.borrow_mut().wake_all();
+    }
+}
+
+impl fmt::Debug for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Signal").field("kind", &self.kind).finish()
+    }
+}
+
+/// The signals subscribed to on one runtime, and the signalfd they are read from.
+///
+/// There is no task driving the signalfd, as that would keep the runtime alive; instead whichever
+/// subscriber polls keeps a read in flight and hands out what it returns.
+pub(crate) struct SignalRegistry {
+    fd: Option<OwnedFd>,
+    mask: libc::sigset_t,
+    read: Option<Op<SigInfoBuf>>,
+    slots: HashMap<libc::c_int, Slot>,
+    failed: bool,
+}
+
+#[derive(Default)]
+struct Slot {
+    received: u64,
+    wakers: Vec<Waker>,
+}
+
+impl SignalRegistry {
+    pub(crate) fn new() -> Self {
+        let mut mask = unsafe { mem::zeroed() };
+
+        unsafe { libc::sigemptyset(&mut mask) };
+
+        Self {
+            fd: None,
+            mask,
+            read: None,
+            slots: HashMap::new(),
+            failed: false,
+        }
+    }
+
+    /// Starts routing `kind` to the signalfd, returning its delivery count so far.
+    fn register(&mut self, kind: SignalKind) -> io::Result<u64> {
+        if let Some(slot) = self.slots.get(&kind.0) {
+            return Ok(slot.received);
+        }
+
+        if matches!(
+            kind.0,
+            libc::SIGKILL | libc::SIGSTOP | libc::SIGSEGV | libc::SIGBUS | libc::SIGILL
+        ) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "signal can't be handled through a signalfd",
+            ));
+        }
+
+        let mut mask = self.mask;
+
+        if unsafe { libc::sigaddset(&mut mask, kind.0) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut single = unsafe { mem::zeroed() };
+
+        unsafe {
+            libc::sigemptyset(&mut single);
+            libc::sigaddset(&mut single, kind.0);
+        }
+
+        let res = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &single, std::ptr::null_mut()) };
+
+        if res != 0 {
+            return Err(io::Error::from_raw_os_error(res));
+        }
+
+        // passing the existing fd updates its mask in place
+        let existing = self.fd.as_ref().map_or(-1, |fd| fd.as_raw_fd());
+
+        let fd = unsafe { libc::signalfd(existing, &mask, libc::SFD_CLOEXEC | libc::SFD_NONBLOCK) };
+
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if existing == -1 {
+            self.fd = Some(unsafe { OwnedFd::from_raw_fd(fd) });
+        }
+
+        self.mask = mask;
+        self.slots.insert(kind.0, Slot::default());
+
+        Ok(0)
+    }
+
+    /// Keeps a read of the signalfd in flight, with `cx` as the task it wakes, and hands out any
+    /// signals it returned.
+    fn poll_read(&mut self, cx: &mut Context<'_>) {
+        while !self.failed {
+            let op = match &mut self.read {
+                Some(op) => op,
+                None => {
+                    let fd = self.fd.as_ref().unwrap().as_raw_fd();
+
+                    let mut buf: SigInfoBuf = Box::new(unsafe { mem::zeroed() });
+
+                    let entry = io_uring::opcode::Read::new(
+                        io_uring::types::Fd(fd),
+                        buf.as_mut_ptr() as *mut u8,
+                        mem::size_of_val(&*buf) as _,
+                    )
+                    .build();
+
+                    match unsafe { submit_op(entry, buf) } {
+                        Ok(op) => self.read.insert(op),
+                        Err(_) => {
+                            self.fail();
+                            return;
+                        }
+                    }
+                }
+            };
+
+            let (entry, buf) = match Pin::new(op).poll(cx) {
+                Poll::Ready(out) => out,
+                Poll::Pending => return,
+            };
+
+            self.read = None;
+
+            match crate::io::cqe_result(&entry) {
+                Ok(len) => {
+                    let count = len as usize / mem::size_of::<libc::signalfd_siginfo>();
+
+                    for info in &buf[..count] {
+                        if let Some(slot) = self.slots.get_mut(&(info.ssi_signo as libc::c_int)) {
+                            slot.received += 1;
+                        }
+                    }
+
+                    self.wake_all();
+                }
+                // the fd is non-blocking in case another reader got there first
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(_) => self.fail(),
+            }
+        }
+    }
+
+    fn fail(&mut self) {
+        self.failed = true;
+        self.wake_all();
+    }
+
+    fn wake_all(&mut self) {
+        for slot in self.slots.values_mut() {
+            for waker in slot.wakers.drain(..) {
+                waker.wake();
+            }
+        }
+    }
+}
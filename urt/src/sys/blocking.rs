@@ -0,0 +1,213 @@
+use crate::sys::notify::{Notified, Notifier};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use std::{mem, ptr, thread};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A pool of threads for work that would otherwise block the ring thread.
+///
+/// Threads are started lazily, up to `max_threads`, and exit after sitting idle for `keep_alive`.
+#[derive(Clone)]
+pub(crate) struct BlockingPool {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    state: Mutex<State>,
+    condvar: Condvar,
+    max_threads: usize,
+    keep_alive: Duration,
+}
+
+struct State {
+    queue: VecDeque<Job>,
+    threads: usize,
+    // waiting threads that no job has been handed to yet
+    idle: usize,
+    // wakeups sent to idle threads that they have yet to pick up
+    notified: usize,
+    shutdown: bool,
+}
+
+impl BlockingPool {
+    pub(crate) fn new(max_threads: usize, keep_alive: Duration) -> Self {
+        assert!(max_threads > 0, "Blocking pool needs at least one thread");
+
+        Self {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State {
+                    queue: VecDeque::new(),
+                    threads: 0,
+                    idle: 0,
+                    notified: 0,
+                    shutdown: false,
+                }),
+                condvar: Condvar::new(),
+                max_threads,
+                keep_alive,
+            }),
+        }
+    }
+
+    pub(crate) fn execute(&self, job: Job) {
+        let mut state = self.inner.state.lock().unwrap();
+
+        state.queue.push_back(job);
+
+        // each idle thread is woken for one job only, so a burst spreads over new threads rather
+        // than queueing behind the one that was idle
+        if state.idle > 0 {
+            state.idle -= 1;
+            state.notified += 1;
+
+            self.inner.condvar.notify_one();
+        } else if state.threads < self.inner.max_threads {
+            state.threads += 1;
+
+            let inner = self.inner.clone();
+
+            // the thread inherits the blocked mask, so a process-directed signal can't land on it
+            // and kill the process instead of reaching the runtime's signalfd
+            let spawned = with_signals_blocked(|| {
+                thread::Builder::new()
+                    .name("urt-blocking".into())
+                    .spawn(move || inner.run())
+            });
+
+            if spawned.is_err() {
+                // queued jobs are still picked up by the threads that already exist
+                state.threads -= 1;
+            }
+        }
+    }
+
+    /// Lets idle threads exit once the queue is drained. Jobs already queued still run.
+    pub(crate) fn shutdown(&self) {
+        self.inner.state.lock().unwrap().shutdown = true;
+        self.inner.condvar.notify_all();
+    }
+}
+
+impl Inner {
+    fn run(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            if let Some(job) = state.queue.pop_front() {
+                drop(state);
+
+                job();
+
+                state = self.state.lock().unwrap();
+
+                continue;
+            }
+
+            if state.shutdown {
+                break;
+            }
+
+            state.idle += 1;
+
+            let (guard, timeout) = self.condvar.wait_timeout(state, self.keep_alive).unwrap();
+
+            state = guard;
+
+            // a spurious wakeup or timeout leaves the thread counted as idle
+            if state.notified > 0 {
+                state.notified -= 1;
+            } else {
+                state.idle -= 1;
+            }
+
+            if timeout.timed_out() && state.queue.is_empty() {
+                break;
+            }
+        }
+
+        state.threads -= 1;
+    }
+}
+
+/// Calls `f` with every signal blocked on the calling thread, restoring the mask afterwards.
+fn with_signals_blocked<R>(f: impl FnOnce() -> R) -> R {
+    let mut all = unsafe { mem::zeroed() };
+    let mut old = unsafe { mem::zeroed() };
+
+    unsafe {
+        libc::sigfillset(&mut all);
+        libc::pthread_sigmask(libc::SIG_SETMASK, &all, &mut old);
+    }
+
+    let res = f();
+
+    unsafe { libc::pthread_sigmask(libc::SIG_SETMASK, &old, ptr::null_mut()) };
+
+    res
+}
+
+/// Runs `f` on `pool`, resolving on the ring thread once it has finished.
+pub(crate) fn run_blocking<F, T>(
+    pool: &BlockingPool,
+    notifier: Arc<Notifier>,
+    notified: Notified,
+    f: F,
+) -> Blocking<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let slot = Arc::new(Mutex::new(None));
+
+    let key = notified.key();
+    let job_slot = slot.clone();
+
+    pool.execute(Box::new(move || {
+        let res = panic::catch_unwind(AssertUnwindSafe(f));
+
+        *job_slot.lock().unwrap() = Some(res);
+
+        notifier.notify(key);
+    }));
+
+    Blocking { notified, slot }
+}
+
+/// The result of a closure running on the blocking pool. Panics in the closure are resumed
+/// here.
+pub(crate) struct Blocking<T> {
+    notified: Notified,
+    slot: Arc<Mutex<Option<thread::Result<T>>>>,
+}
+
+impl<T> Blocking<T> {
+    /// Like polling the future, but hands back a panic in the closure instead of resuming it.
+    pub(crate) fn poll_result(&mut self, cx: &mut Context<'_>) -> Poll<thread::Result<T>> {
+        futures::ready!(Pin::new(&mut self.notified).poll(cx));
+
+        Poll::Ready(
+            self.slot
+                .lock()
+                .unwrap()
+                .take()
+                .expect("Polled after completed"),
+        )
+    }
+}
+
+impl<T> Future for Blocking<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match futures::ready!(self.poll_result(cx)) {
+            Ok(out) => Poll::Ready(out),
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+}
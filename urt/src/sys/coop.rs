@@ -0,0 +1,69 @@
+use std::cell::Cell;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// How many ops a task may complete in one poll before it is made to yield, like tokio's coop
+/// budget.
+const BUDGET: u8 = 128;
+
+thread_local!(static REMAINING: Cell<Option<u8>> = const { Cell::new(None) });
+
+/// Runs `f`, which polls a task, with a fresh budget. Outside of this, e.g. when polling from
+/// `block_on`-style code, the budget is unlimited.
+pub(crate) fn with_budget<R>(f: impl FnOnce() -> R) -> R {
+    struct Reset(Option<u8>);
+
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            REMAINING.with(|remaining| remaining.set(self.0));
+        }
+    }
+
+    let _reset = Reset(REMAINING.with(|remaining| remaining.replace(Some(BUDGET))));
+
+    f()
+}
+
+/// Returns `Pending`, after scheduling the task to be polled again, if its budget is spent.
+#[inline]
+pub(crate) fn poll_budget(cx: &mut Context<'_>) -> Poll<()> {
+    REMAINING.with(|remaining| match remaining.get() {
+        Some(0) => {
+            cx.waker().wake_by_ref();
+
+            Poll::Pending
+        }
+        _ => Poll::Ready(()),
+    })
+}
+
+/// Uses up one unit of the current task's budget.
+#[inline]
+pub(crate) fn consume() {
+    REMAINING.with(|remaining| {
+        if let Some(n) = remaining.get() {
+            remaining.set(Some(n.saturating_sub(1)));
+        }
+    })
+}
+
+/// Waits until the current task has budget left, then uses up one unit of it. For loops that
+/// can make progress without ever returning `Pending`.
+pub(crate) fn proceed() -> Proceed {
+    Proceed
+}
+
+pub(crate) struct Proceed;
+
+impl Future for Proceed {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        futures::ready!(poll_budget(cx));
+
+        consume();
+
+        Poll::Ready(())
+    }
+}
@@ -1,32 +1,196 @@
+use crate::rt::RuntimeMetrics;
+use crate::sys::coop;
+use crate::sys::metrics::{self, Metrics};
+use crate::sys::notify::{self, Notified, Notifier, Signal};
+use crate::sys::trace;
+use crate::time;
 use io_uring::{cqueue, squeue, IoUring};
 use slab::Slab;
 use std::any::Any;
 use std::cell::RefCell;
 use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::io::AsRawFd;
 use std::pin::Pin;
 use std::rc::Rc;
+use std::sync::Arc;
 use std::task::{Context, Poll, Waker};
-use std::{io, mem};
+use std::time::Instant;
+use std::{hint, io, mem};
+
+/// `user_data` of the read kept armed on the notifier's eventfd. Slab keys never get this large.
+const NOTIFY_KEY: u64 = u64::MAX;
+
+/// `user_data` of the cancellations submitted on shutdown, whose completions are ignored.
+const CANCEL_KEY: u64 = u64::MAX - 1;
+
+/// `user_data` of the timeout bounding how long shutdown waits for cancellations.
+const DEADLINE_KEY: u64 = u64::MAX - 2;
 
 pub(crate) enum Lifetime {
     Submitted,
     Waiting(Waker),
+    // owns the op data so buffers outlive the kernel's use of them
+    #[allow(dead_code)]
     Cancelled(Box<dyn Any>),
     Completed(io_uring::cqueue::Entry),
 }
 
 pub(crate) struct Driver {
     slab: Rc<RefCell<Slab<Lifetime>>>,
-    uring: IoUring,
+    uring: Ring,
+    notifier: Arc<Notifier>,
+    notify_buf: Box<u64>,
+    notify_armed: bool,
+    signals: Rc<RefCell<Slab<Signal>>>,
+    closing: bool,
 }
 
 impl Driver {
-    pub(crate) fn new(entries: u32) -> io::Result<Self> {
+    pub(crate) fn new(entries: u32, metrics: Rc<Metrics>) -> io::Result<Self> {
         let slab = Rc::new(RefCell::new(Slab::with_capacity(4096)));
 
-        let uring = io_uring::Builder::default().dontfork().build(entries)?;
+        let uring = Ring {
+            uring: IoUring::builder().dontfork().build(entries)?,
+            metrics,
+        };
+
+        let mut driver = Self {
+            slab,
+            uring,
+            notifier: Arc::new(Notifier::new()?),
+            notify_buf: Box::new(0),
+            notify_armed: false,
+            signals: Rc::new(RefCell::new(Slab::new())),
+            closing: false,
+        };
+
+        driver.arm_notifier()?;
+
+        Ok(driver)
+    }
+
+    pub(crate) fn metrics(&self) -> RuntimeMetrics {
+        let slab = self.slab.borrow();
+
+        self.uring.metrics.snapshot(slab.len(), slab.capacity())
+    }
+
+    /// Returns the handle other threads use to wake this driver.
+    pub(crate) fn notifier(&self) -> Arc<Notifier> {
+        self.notifier.clone()
+    }
+
+    /// Registers a future which completes once [`Notifier::notify`] is called with its key.
+    pub(crate) fn notified(&self) -> Notified {
+        Notified::new(self.signals.clone())
+    }
+
+    fn arm_notifier(&mut self) -> io::Result<()> {
+        let fd = io_uring::types::Fd(self.notifier.as_raw_fd());
+
+        let entry = io_uring::opcode::Read::new(
+            fd,
+            &mut *self.notify_buf as *mut u64 as *mut u8,
+            mem::size_of::<u64>() as _,
+        )
+        .build();
+
+        unsafe { self.uring.push(entry, NOTIFY_KEY)? };
+
+        self.notify_armed = true;
+
+        Ok(())
+    }
 
-        Ok(Self { slab, uring })
+    /// Asks the kernel to cancel every op it may still be using memory for, including the
+    /// notifier read, which is no longer re-armed afterwards.
+    pub(crate) fn cancel_all(&mut self) -> io::Result<()> {
+        self.closing = true;
+
+        let mut keys: Vec<u64> = self
+            .slab
+            .borrow()
+            .iter()
+            .filter(|(_, lifetime)| !matches!(lifetime, Lifetime::Completed(_)))
+            .map(|(key, _)| key as u64)
+            .collect();
+
+        if self.notify_armed {
+            keys.push(NOTIFY_KEY);
+        }
+
+        for key in keys {
+            let entry = io_uring::opcode::AsyncCancel::new(key).build();
+
+            unsafe { self.uring.push(entry, CANCEL_KEY)? };
+        }
+
+        self.uring.submit()?;
+
+        Ok(())
+    }
+
+    /// How many ops the kernel has not yet completed.
+    fn in_flight(&self) -> usize {
+        let slab = self.slab.borrow();
+
+        let ops = slab
+            .iter()
+            .filter(|(_, lifetime)| !matches!(lifetime, Lifetime::Completed(_)))
+            .count();
+
+        ops + self.notify_armed as usize
+    }
+
+    /// Processes completions until nothing is in flight or `deadline` passes, returning whether
+    /// everything completed.
+    pub(crate) fn drain(&mut self, deadline: Instant) -> io::Result<bool> {
+        let mut timeout_armed = false;
+
+        loop {
+            self.complete()?;
+
+            if self.in_flight() == 0 {
+                return Ok(true);
+            }
+
+            let now = Instant::now();
+
+            if now >= deadline {
+                return Ok(false);
+            }
+
+            // bounds the wait below even if an op ignores its cancellation; the kernel copies
+            // the timespec when the op is submitted
+            let ts = time::timespec(deadline - now);
+
+            if !timeout_armed {
+                let entry = io_uring::opcode::Timeout::new(&ts).build();
+
+                unsafe { self.uring.push(entry, DEADLINE_KEY)? };
+
+                timeout_armed = true;
+            }
+
+            match self.uring.submit_and_wait(1) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                res => {
+                    res?;
+                }
+            }
+        }
+    }
+
+    /// Gives up on ops the kernel still holds by leaking the memory they may write to, so it is
+    /// never freed under them. An op dropped after this still moves its data into the leaked slab,
+    /// so whatever owns it, such as the signal registry's pending read, can be dropped as usual.
+    pub(crate) fn leak_in_flight(&mut self) {
+        mem::forget(self.slab.clone());
+
+        if self.notify_armed {
+            mem::forget(mem::replace(&mut self.notify_buf, Box::new(0)));
+        }
     }
 
     #[inline]
@@ -40,11 +204,7 @@ impl Driver {
 
         let key = vacant.key();
 
-        let entry = entry.user_data(key as _);
-
-        while self.uring.submission().push(&entry).is_err() {
-            self.uring.submit()?;
-        }
+        self.uring.push(entry, key as _)?;
 
         vacant.insert(Lifetime::Submitted);
 
@@ -63,16 +223,62 @@ impl Driver {
     pub(crate) fn poll(&mut self) -> io::Result<()> {
         self.uring.submit()?;
 
-        self.complete();
+        self.complete()?;
 
         Ok(())
     }
 
+    /// Whether at least half the submission queue is waiting to be submitted.
+    #[inline]
+    pub(crate) fn submission_half_full(&self) -> bool {
+        let sq = unsafe { self.uring.submission_shared() };
+
+        sq.len() * 2 >= sq.capacity()
+    }
+
+    /// Submits, then checks the completion queue without entering the kernel again until
+    /// something completes or `deadline` passes. Returns whether anything completed.
+    pub(crate) fn spin(&mut self, deadline: Option<Instant>) -> io::Result<bool> {
+        self.uring.submit()?;
+
+        let start = Instant::now();
+
+        let completed = loop {
+            if self.complete()? {
+                break true;
+            }
+
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                break false;
+            }
+
+            hint::spin_loop();
+        };
+
+        let metrics = &self.uring.metrics;
+
+        metrics::add(&metrics.spins, 1);
+        metrics
+            .spin_time
+            .set(metrics.spin_time.get() + start.elapsed());
+
+        Ok(completed)
+    }
+
     pub(crate) fn park(&mut self) -> io::Result<()> {
-        if !self.complete() {
+        if !self.complete()? {
+            let start = Instant::now();
+
             self.uring.submit_and_wait(1)?;
 
-            self.complete();
+            let metrics = &self.uring.metrics;
+
+            metrics::add(&metrics.parks, 1);
+            metrics
+                .park_time
+                .set(metrics.park_time.get() + start.elapsed());
+
+            self.complete()?;
         } else {
             self.uring.submit()?;
         }
@@ -80,7 +286,11 @@ impl Driver {
         Ok(())
     }
 
-    pub(crate) fn complete(&mut self) -> bool {
+    pub(crate) fn complete(&mut self) -> io::Result<bool> {
+        let mut notified = false;
+
+        let metrics = self.uring.metrics.clone();
+
         let mut completions = self.uring.completion();
         let mut slab = self.slab.borrow_mut();
 
@@ -88,7 +298,20 @@ impl Driver {
 
         let res = !completions.is_empty();
 
+        metrics::add(&metrics.cqes_reaped, completions.len() as u64);
+
         for c in completions {
+            trace::cqe(&c);
+
+            match c.user_data() {
+                NOTIFY_KEY => {
+                    notified = true;
+                    continue;
+                }
+                CANCEL_KEY | DEADLINE_KEY => continue,
+                _ => {}
+            }
+
             let key = c.user_data() as usize;
 
             let old_lifetime = mem::replace(slab.get_mut(key).unwrap(), Lifetime::Completed(c));
@@ -99,6 +322,8 @@ impl Driver {
                     waker.wake();
                 }
                 Lifetime::Cancelled(_) => {
+                    metrics::add(&metrics.cancelled_ops, 1);
+
                     let _ = slab.remove(key);
                 }
                 Lifetime::Completed(_) => {
@@ -107,7 +332,80 @@ impl Driver {
             }
         }
 
-        res
+        mem::drop(slab);
+
+        if notified {
+            self.notify_armed = false;
+
+            let mut signals = self.signals.borrow_mut();
+
+            for key in self.notifier.take_pending() {
+                notify::deliver(&mut signals, key);
+            }
+
+            mem::drop(signals);
+
+            if !self.closing {
+                self.arm_notifier()?;
+            }
+        }
+
+        Ok(res)
+    }
+}
+
+/// The ring, counting what goes through it.
+struct Ring {
+    uring: IoUring,
+    metrics: Rc<Metrics>,
+}
+
+impl Ring {
+    /// Pushes `entry` onto the submission queue, tagged with `user_data`, submitting to make room
+    /// while it is full.
+    ///
+    /// # Safety
+    /// As for [`io_uring::SubmissionQueue::push`].
+    unsafe fn push(&mut self, entry: squeue::Entry, user_data: u64) -> io::Result<()> {
+        let entry = entry.user_data(user_data);
+
+        trace::sqe(&entry, user_data);
+
+        while self.uring.submission().push(&entry).is_err() {
+            metrics::add(&self.metrics.sq_full_retries, 1);
+
+            self.submit()?;
+        }
+
+        Ok(())
+    }
+
+    fn submit(&self) -> io::Result<usize> {
+        self.submit_and_wait(0)
+    }
+
+    fn submit_and_wait(&self, want: usize) -> io::Result<usize> {
+        metrics::add(&self.metrics.submit_calls, 1);
+
+        let submitted = self.uring.submit_and_wait(want)?;
+
+        metrics::add(&self.metrics.sqes_submitted, submitted as u64);
+
+        Ok(submitted)
+    }
+}
+
+impl Deref for Ring {
+    type Target = IoUring;
+
+    fn deref(&self) -> &IoUring {
+        &self.uring
+    }
+}
+
+impl DerefMut for Ring {
+    fn deref_mut(&mut self) -> &mut IoUring {
+        &mut self.uring
     }
 }
 
@@ -129,6 +427,9 @@ where
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let this = &mut *self;
 
+        // a task whose ops keep completing immediately would otherwise never yield
+        futures::ready!(coop::poll_budget(cx));
+
         let mut slab = this.slab.borrow_mut();
 
         let lifetime = slab.get_mut(this.key).unwrap();
@@ -0,0 +1,47 @@
+use crate::rt::RuntimeMetrics;
+use std::cell::Cell;
+use std::time::Duration;
+
+/// A worker's counters. Everything that updates them runs on the worker's thread, so they are
+/// plain cells rather than atomics.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    pub(crate) tasks_spawned: Cell<u64>,
+    pub(crate) tasks_completed: Cell<u64>,
+    pub(crate) polls: Cell<u64>,
+    pub(crate) parks: Cell<u64>,
+    pub(crate) park_time: Cell<Duration>,
+    pub(crate) spins: Cell<u64>,
+    pub(crate) spin_time: Cell<Duration>,
+    pub(crate) sqes_submitted: Cell<u64>,
+    pub(crate) cqes_reaped: Cell<u64>,
+    pub(crate) submit_calls: Cell<u64>,
+    pub(crate) sq_full_retries: Cell<u64>,
+    pub(crate) cancelled_ops: Cell<u64>,
+}
+
+#[inline]
+pub(crate) fn add(counter: &Cell<u64>, n: u64) {
+    counter.set(counter.get().wrapping_add(n));
+}
+
+impl Metrics {
+    pub(crate) fn snapshot(&self, op_slots: usize, op_slot_capacity: usize) -> RuntimeMetrics {
+        RuntimeMetrics {
+            tasks_spawned: self.tasks_spawned.get(),
+            tasks_completed: self.tasks_completed.get(),
+            polls: self.polls.get(),
+            parks: self.parks.get(),
+            park_time: self.park_time.get(),
+            spins: self.spins.get(),
+            spin_time: self.spin_time.get(),
+            sqes_submitted: self.sqes_submitted.get(),
+            cqes_reaped: self.cqes_reaped.get(),
+            submit_calls: self.submit_calls.get(),
+            sq_full_retries: self.sq_full_retries.get(),
+            cancelled_ops: self.cancelled_ops.get(),
+            op_slots,
+            op_slot_capacity,
+        }
+    }
+}
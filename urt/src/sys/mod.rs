@@ -1,8 +1,10 @@
+use futures::future;
 use io_uring::squeue;
 use std::cell::RefCell;
 use std::future::Future;
 use std::io;
 use std::rc::Rc;
+use std::sync::Arc;
 
 mod scheduler;
 
@@ -14,12 +16,60 @@ mod driver;
 
 mod waker;
 
-thread_local!(pub(crate) static CONTEXT: RefCell<Option<ThreadContext>> = RefCell::new(None));
+mod notify;
+
+mod blocking;
+
+pub(crate) mod coop;
+
+mod metrics;
+
+mod trace;
+
+thread_local!(pub(crate) static CONTEXT: RefCell<Option<ThreadContext>> = const { RefCell::new(None) });
 
 pub(crate) struct ThreadContext {
     pub(crate) spawner: Spawner,
     pub(crate) driver: Rc<RefCell<Driver>>,
     pub(crate) scheduler: Rc<RefCell<Scheduler>>,
+    pub(crate) blocking: BlockingPool,
+    pub(crate) pipes: Rc<RefCell<PipeCache>>,
+    pub(crate) signals: Rc<RefCell<SignalRegistry>>,
+    pub(crate) remote: Arc<Remote>,
+    pub(crate) resolver: Option<Resolver>,
+}
+
+/// Why there is no [`ThreadContext`], for panics and [`TryCurrentError`](crate::rt::TryCurrentError).
+pub(crate) const NO_CONTEXT: &str =
+    "no urt runtime on this thread; this must be called from a task, or on the thread of a Runtime \
+     inside `run`";
+
+/// Calls `f` with the current thread's context.
+///
+/// # Panics
+/// Panics outside of a runtime, with a message saying so.
+pub(crate) fn with_context<R>(f: impl FnOnce(&ThreadContext) -> R) -> R {
+    CONTEXT.with(|maybe| {
+        let borrow = maybe.borrow();
+        let context = borrow.as_ref().expect(NO_CONTEXT);
+
+        f(context)
+    })
+}
+
+/// Calls `f` with the current thread's context if it belongs to the worker behind `remote`, i.e.
+/// this is that worker's thread and it is inside `run`.
+pub(crate) fn with_worker_context<R>(
+    remote: &Arc<Remote>,
+    f: impl FnOnce(&ThreadContext) -> R,
+) -> Option<R> {
+    CONTEXT
+        .try_with(|maybe| match &*maybe.borrow() {
+            Some(context) if Arc::ptr_eq(&context.remote, remote) => Some(f(context)),
+            _ => None,
+        })
+        .ok()
+        .flatten()
 }
 
 pub fn spawn<T, F>(fut: F) -> JoinHandle<T>
@@ -27,34 +77,99 @@ where
     F: Future<Output = T> + 'static,
     T: Send + 'static + Sync,
 {
-    CONTEXT.with(|maybe| {
-        let borrow = maybe.borrow();
-        let context = borrow.as_ref().unwrap();
+    let (task, handle) = Task::new(fut);
 
-        let (task, handle) = Task::new(fut);
+    spawn_task(task);
 
-        context.spawner.spawn(task);
+    handle
+}
 
-        handle
+/// Hands `task` to the current worker, which starts polling it at its next tick.
+pub(crate) fn spawn_task(task: Task) {
+    with_context(|context| {
+        context.spawner.spawn(task);
     })
 }
 
+/// Pushes `entry` onto the current worker's submission queue, keeping `data` alive until the
+/// completion arrives.
+///
+/// # Safety
+/// Any buffers or pointers referenced by `entry` must be owned by `data` (or otherwise outlive
+/// the op), since the kernel may keep using them after the returned [`Op`] is dropped.
 pub unsafe fn submit_op<T>(entry: squeue::Entry, data: T) -> io::Result<Op<T>>
 where
     T: 'static,
 {
-    CONTEXT.with(|maybe| {
-        let borrow = maybe.borrow();
-        let context = borrow.as_ref().unwrap();
-
+    with_context(|context| {
         let x = context.driver.borrow_mut().push(entry, data);
 
+        coop::consume();
+
         x
     })
 }
 
+/// Runs `f` on the current runtime's blocking pool, so blocking syscalls and CPU-heavy work do
+/// not stall the ring thread.
+///
+/// The pool grows lazily up to the limit set with
+/// [`Builder::max_blocking_threads`](crate::rt::Builder::max_blocking_threads); once every thread
+/// is busy further closures queue until one frees up. A panic in `f` is resumed in whichever task
+/// awaits the returned handle.
+pub fn spawn_blocking<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let mut blocking = run_blocking(f);
+
+    // the result is picked up by a task on the ring thread, woken through the driver's notifier
+    let (task, handle) = Task::with_result(future::poll_fn(move |cx| blocking.poll_result(cx)));
+
+    spawn_task(task);
+
+    handle
+}
+
+/// Runs `f` on the current runtime's blocking pool, resolving on the ring thread.
+pub(crate) fn run_blocking<F, T>(f: F) -> Blocking<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    with_context(|context| {
+        let driver = context.driver.borrow();
+
+        blocking::run_blocking(&context.blocking, driver.notifier(), driver.notified(), f)
+    })
+}
+
+/// Takes a pipe from the current worker's cache, creating one if it is empty.
+pub(crate) fn take_pipe() -> io::Result<Rc<(Sender, Receiver)>> {
+    with_context(|context| {
+        let pipe = context.pipes.borrow_mut().take()?;
+
+        Ok(pipe)
+    })
+}
+
+/// Hands a pipe back to the current worker's cache. The pipe must be empty, and is dropped
+/// instead if any op still holds it.
+pub(crate) fn return_pipe(pipe: Rc<(Sender, Receiver)>) {
+    with_context(|context| {
+        context.pipes.borrow_mut().give(pipe);
+    })
+}
+
+use crate::net::Resolver;
+use crate::pipe::{PipeCache, Receiver, Sender};
+use crate::signal::SignalRegistry;
 use crate::task::JoinHandle;
+pub(crate) use blocking::{Blocking, BlockingPool};
 pub(crate) use driver::*;
+pub(crate) use metrics::Metrics;
+pub(crate) use notify::Notifier;
 pub(crate) use rt::*;
 pub(crate) use scheduler::*;
 pub(crate) use task::*;
@@ -0,0 +1,165 @@
+use slab::Slab;
+use std::cell::RefCell;
+use std::future::Future;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::task::{Context, Poll, Waker};
+use std::{io, mem};
+
+/// The thread-safe half of the driver's cross-thread wakeups.
+///
+/// Other threads record which [`Notified`] futures are ready and then poke an eventfd that the
+/// driver keeps a read armed on, so a parked ring wakes up to deliver them.
+pub(crate) struct Notifier {
+    fd: OwnedFd,
+    pending: Mutex<Vec<usize>>,
+}
+
+impl Notifier {
+    pub(crate) fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            pending: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Marks the [`Notified`] registered under `key` as ready and wakes the ring.
+    pub(crate) fn notify(&self, key: usize) {
+        self.pending.lock().unwrap().push(key);
+
+        self.wake();
+    }
+
+    /// Wakes the ring without completing anything.
+    pub(crate) fn wake(&self) {
+        let one = 1u64;
+
+        // the only possible failure is the counter saturating, in which case a wakeup is already
+        // pending anyway
+        let _ = unsafe {
+            libc::write(
+                self.fd.as_raw_fd(),
+                &one as *const u64 as *const _,
+                mem::size_of::<u64>(),
+            )
+        };
+    }
+
+    pub(crate) fn take_pending(&self) -> Vec<usize> {
+        mem::take(&mut *self.pending.lock().unwrap())
+    }
+}
+
+impl AsRawFd for Notifier {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+pub(crate) enum Signal {
+    Registered,
+    Waiting(Waker),
+    Cancelled,
+    Notified,
+}
+
+/// Resolves once another thread calls [`Notifier::notify`] with this future's key.
+pub(crate) struct Notified {
+    signals: Rc<RefCell<Slab<Signal>>>,
+    key: usize,
+    done: bool,
+}
+
+impl Notified {
+    pub(crate) fn new(signals: Rc<RefCell<Slab<Signal>>>) -> Self {
+        let key = signals.borrow_mut().insert(Signal::Registered);
+
+        Self {
+            signals,
+            key,
+            done: false,
+        }
+    }
+
+    pub(crate) fn key(&self) -> usize {
+        self.key
+    }
+}
+
+impl Future for Notified {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+
+        assert!(!this.done, "Polled after completed");
+
+        let mut signals = this.signals.borrow_mut();
+
+        let signal = signals.get_mut(this.key).unwrap();
+
+        match signal {
+            Signal::Registered => {
+                *signal = Signal::Waiting(cx.waker().clone());
+
+                Poll::Pending
+            }
+            Signal::Waiting(waker) => {
+                if !cx.waker().will_wake(waker) {
+                    *waker = cx.waker().clone();
+                }
+
+                Poll::Pending
+            }
+            Signal::Cancelled => {
+                panic!("How are we polling a canceled notification?");
+            }
+            Signal::Notified => {
+                let _ = signals.remove(this.key);
+
+                this.done = true;
+
+                Poll::Ready(())
+            }
+        }
+    }
+}
+
+impl Drop for Notified {
+    fn drop(&mut self) {
+        if !self.done {
+            let mut signals = self.signals.borrow_mut();
+
+            let signal = mem::replace(signals.get_mut(self.key).unwrap(), Signal::Cancelled);
+
+            // keep the slot reserved until the notification arrives, so the key is not reused
+            if matches!(signal, Signal::Notified) {
+                let _ = signals.remove(self.key);
+            }
+        }
+    }
+}
+
+/// Delivers a notification for `key` on the ring thread.
+pub(crate) fn deliver(signals: &mut Slab<Signal>, key: usize) {
+    let old = mem::replace(signals.get_mut(key).unwrap(), Signal::Notified);
+
+    match old {
+        Signal::Registered => {}
+        Signal::Waiting(waker) => waker.wake(),
+        Signal::Cancelled => {
+            let _ = signals.remove(key);
+        }
+        Signal::Notified => {
+            unreachable!("Notification {key} delivered twice");
+        }
+    }
+}
@@ -1,19 +1,50 @@
-use crate::sys::{Driver, Scheduler, Task};
+use crate::net::Resolver;
+use crate::pipe::PipeCache;
+use crate::signal::SignalRegistry;
+use crate::sys::metrics::{self, Metrics};
+use crate::sys::{BlockingPool, Driver, Notifier, Released, Scheduler, Task, TaskList, Woken};
 
-use crate::sys::waker::make_waker;
-use slab::Slab;
+use crate::sys::coop;
+use crate::sys::waker::waker_ref;
 use std::cell::RefCell;
 use std::rc::Rc;
 
+use std::cell::Cell;
 use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::task::Context;
+use std::time::{Duration, Instant};
 use std::{io, mem};
 
 pub(crate) struct Worker {
-    tasks: Slab<Task>,
+    tasks: TaskList,
     scheduler: Rc<RefCell<Scheduler>>,
     spawner: Spawner,
     driver: Rc<RefCell<Driver>>,
+    blocking: BlockingPool,
+    pipes: Rc<RefCell<PipeCache>>,
+    signals: Rc<RefCell<SignalRegistry>>,
+    stop: Arc<AtomicBool>,
+    policy: PollPolicy,
+    remote: Arc<Remote>,
+    metrics: Rc<Metrics>,
+    resolver: Option<Resolver>,
+}
+
+/// When a worker submits to and reaps from the ring, and how it waits while idle.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PollPolicy {
+    /// How many tasks are polled between reaping completions.
+    pub(crate) interval: u32,
+    /// Also reap as soon as the submission queue is half full.
+    pub(crate) adaptive: bool,
+    /// Spin on the completion queue instead of ever parking.
+    pub(crate) busy_poll: bool,
+    /// How long to spin on the completion queue before parking.
+    pub(crate) spin_before_park: Option<Duration>,
 }
 
 enum Tick {
@@ -25,15 +56,29 @@ enum Tick {
 #[derive(Clone)]
 pub(crate) struct Spawner {
     sender: Rc<RefCell<VecDeque<Task>>>,
+    closed: Rc<Cell<bool>>,
 }
 
 impl Worker {
-    pub(crate) fn new(driver: Rc<RefCell<Driver>>) -> Self {
-        let tasks = Slab::with_capacity(4096);
+    pub(crate) fn new(
+        driver: Rc<RefCell<Driver>>,
+        blocking: BlockingPool,
+        policy: PollPolicy,
+        metrics: Rc<Metrics>,
+        resolver: Option<Resolver>,
+    ) -> Self {
+        let remote = Arc::new(Remote {
+            queue: Mutex::new(RemoteQueue::default()),
+            queued: AtomicBool::new(false),
+            notifier: driver.borrow().notifier(),
+        });
+
+        let tasks = TaskList::default();
         let scheduler = Rc::new(RefCell::new(Scheduler::new()));
 
         let spawner = Spawner {
             sender: Rc::new(RefCell::new(VecDeque::with_capacity(4096))),
+            closed: Rc::new(Cell::new(false)),
         };
 
         Self {
@@ -41,6 +86,14 @@ impl Worker {
             scheduler,
             spawner,
             driver,
+            blocking,
+            pipes: Rc::new(RefCell::new(PipeCache::new())),
+            signals: Rc::new(RefCell::new(SignalRegistry::new())),
+            stop: Arc::new(AtomicBool::new(false)),
+            policy,
+            remote,
+            metrics,
+            resolver,
         }
     }
 
@@ -56,11 +109,42 @@ impl Worker {
         self.scheduler.clone()
     }
 
+    pub(crate) fn blocking(&self) -> BlockingPool {
+        self.blocking.clone()
+    }
+
+    pub(crate) fn pipes(&self) -> Rc<RefCell<PipeCache>> {
+        self.pipes.clone()
+    }
+
+    pub(crate) fn signals(&self) -> Rc<RefCell<SignalRegistry>> {
+        self.signals.clone()
+    }
+
+    pub(crate) fn remote(&self) -> Arc<Remote> {
+        self.remote.clone()
+    }
+
+    pub(crate) fn resolver(&self) -> Option<Resolver> {
+        self.resolver.clone()
+    }
+
+    /// The flag which, once set, makes [`run`](Self::run) return at its next tick.
+    pub(crate) fn stop_flag(&self) -> Arc<AtomicBool> {
+        self.stop.clone()
+    }
+
     pub(crate) fn run(&mut self) -> io::Result<()> {
-        let mut polled_counter = 0u64;
+        let mut polled_counter = 0u32;
 
         loop {
-            if polled_counter == 128 {
+            if self.stop.load(Ordering::Acquire) {
+                return Ok(());
+            }
+
+            if polled_counter >= self.policy.interval
+                || (self.policy.adaptive && self.driver.borrow().submission_half_full())
+            {
                 polled_counter = 0;
                 self.poll()?;
             }
@@ -69,7 +153,11 @@ impl Worker {
                 Tick::Poll => {
                     polled_counter += 1;
                 }
-                Tick::QueueEmpty => self.park()?,
+                Tick::QueueEmpty => {
+                    // idling reaps whatever has completed
+                    polled_counter = 0;
+                    self.idle()?;
+                }
                 Tick::TasksEmpty => {
                     return Ok(());
                 }
@@ -82,34 +170,79 @@ impl Worker {
         self.driver.borrow_mut().poll()
     }
 
-    fn park(&mut self) -> io::Result<()> {
-        self.driver.borrow_mut().park()
+    /// Waits for a completion while no task is ready, by spinning, parking or both.
+    fn idle(&mut self) -> io::Result<()> {
+        let mut driver = self.driver.borrow_mut();
+
+        if self.policy.busy_poll {
+            driver.spin(None)?;
+
+            return Ok(());
+        }
+
+        if let Some(spin) = self.policy.spin_before_park {
+            if driver.spin(Some(Instant::now() + spin))? {
+                return Ok(());
+            }
+        }
+
+        driver.park()
     }
 
     fn tick(&mut self) -> Tick {
         let mut guard = self.scheduler.borrow_mut();
 
         // intake new tasks if present
-        while let Some(t) = self.spawner.sender.borrow_mut().pop_front() {
-            let key = self.tasks.insert(t);
-            guard.spawn(key);
+        while let Some(task) = self.spawner.sender.borrow_mut().pop_front() {
+            metrics::add(&self.metrics.tasks_spawned, 1);
+
+            task.bind(self.remote.clone());
+
+            self.tasks.push(task.clone());
+            guard.spawn(task);
         }
 
-        // try and poll a task if available
-        if let Some(key) = guard.fetch_next_task_for_tick() {
-            let waker = make_waker(key);
+        let (spawned, woken) = self.remote.take();
+
+        for fut in spawned {
+            let (task, _) = Task::new(fut);
+
+            metrics::add(&self.metrics.tasks_spawned, 1);
+
+            task.bind(self.remote.clone());
+            self.tasks.push(task.clone());
+            guard.spawn(task);
+        }
+
+        for task in woken {
+            let task = task.into_task();
+
+            // cleared first, so a wake from now on is queued again
+            task.unset_woken_remotely();
+            guard.spawn(task);
+        }
 
-            if let Some(task) = self.tasks.get_mut(key) {
-                mem::drop(guard);
+        // try and poll a task if available
+        if let Some(task) = guard.fetch_next_task_for_tick() {
+            mem::drop(guard);
 
+            // a task dropped at shutdown may still be queued
+            if !task.is_complete() {
+                let waker = waker_ref(&task);
                 let mut cx = Context::from_waker(&waker);
 
-                if task.poll_task(&mut cx) {
-                    self.tasks.remove(key);
-                }
+                metrics::add(&self.metrics.polls, 1);
 
-                return Tick::Poll;
+                if coop::with_budget(|| task.poll(&mut cx)) {
+                    metrics::add(&self.metrics.tasks_completed, 1);
+
+                    self.tasks.remove(&task);
+                }
             }
+
+            self.scheduler.borrow_mut().finish_tick();
+
+            return Tick::Poll;
         }
 
         // if we couldn't poll, figure out why
@@ -121,8 +254,157 @@ impl Worker {
     }
 }
 
+impl Worker {
+    /// Stops accepting tasks, cancels every op in flight and waits until `deadline` for the
+    /// kernel to let go of them, then drops all tasks.
+    ///
+    /// Must run inside the worker's context, since completing ops wakes tasks. If the kernel still
+    /// holds ops at the deadline, their buffers and the tasks are leaked rather than freed under
+    /// it, and `TimedOut` is returned.
+    pub(crate) fn shutdown(&mut self, deadline: Instant) -> io::Result<()> {
+        self.stop.store(true, Ordering::Release);
+        self.spawner.close();
+        self.remote.close();
+
+        let mut driver = self.driver.borrow_mut();
+
+        driver.cancel_all()?;
+
+        if !driver.drain(deadline)? {
+            driver.leak_in_flight();
+
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "ops were still in flight at the shutdown deadline",
+            ));
+        }
+
+        mem::drop(driver);
+
+        // task destructors may use the context, and wake or drop other tasks
+        while let Some(task) = self.tasks.pop_front() {
+            task.shutdown();
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.blocking.shutdown();
+    }
+}
+
+/// A future spawned from another thread, boxed to cross over.
+pub(crate) type RemoteTask = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// The part of a worker that other threads spawn onto, and hand its tasks back through when they
+/// wake them or drop their last waker.
+pub(crate) struct Remote {
+    queue: Mutex<RemoteQueue>,
+    // lets the worker skip the lock on every tick
+    queued: AtomicBool,
+    notifier: Arc<Notifier>,
+}
+
+#[derive(Default)]
+struct RemoteQueue {
+    tasks: Vec<RemoteTask>,
+    woken: Vec<Woken>,
+    released: Vec<Released>,
+    closed: bool,
+}
+
+impl Remote {
+    /// Queues `fut` for the worker and wakes its ring if it is parked. Once the worker is
+    /// shutting down, `fut` is dropped instead.
+    pub(crate) fn spawn(&self, fut: RemoteTask) {
+        self.push(fut, |queue| &mut queue.tasks);
+    }
+
+    /// Schedules `task`, one of the worker's, from another thread.
+    pub(crate) fn wake(&self, task: Task) {
+        // already on its way
+        if task.set_woken_remotely() {
+            self.push(Woken::new(task), |queue| &mut queue.woken);
+        }
+    }
+
+    /// Frees `cell`, one of the worker's, on the worker's thread.
+    pub(crate) fn release(&self, cell: Released) {
+        self.push(cell, |queue| &mut queue.released);
+    }
+
+    fn push<T>(&self, item: T, list: impl FnOnce(&mut RemoteQueue) -> &mut Vec<T>) {
+        let mut queue = self.queue.lock().unwrap();
+
+        if queue.closed {
+            // dropped outside the lock
+            mem::drop(queue);
+            mem::drop(item);
+
+            return;
+        }
+
+        // otherwise the wakeup for the first one has yet to be picked up
+        let first = queue.tasks.is_empty() && queue.woken.is_empty() && queue.released.is_empty();
+
+        list(&mut queue).push(item);
+
+        self.queued.store(true, Ordering::Release);
+
+        mem::drop(queue);
+
+        if first {
+            self.notifier.wake();
+        }
+    }
+
+    fn take(&self) -> (Vec<RemoteTask>, Vec<Woken>) {
+        if !self.queued.swap(false, Ordering::Acquire) {
+            return (Vec::new(), Vec::new());
+        }
+
+        let mut queue = self.queue.lock().unwrap();
+
+        let (tasks, woken) = (mem::take(&mut queue.tasks), mem::take(&mut queue.woken));
+        let released = mem::take(&mut queue.released);
+
+        mem::drop(queue);
+        mem::drop(released);
+
+        (tasks, woken)
+    }
+
+    fn close(&self) {
+        let mut queue = self.queue.lock().unwrap();
+
+        queue.closed = true;
+
+        let taken = (
+            mem::take(&mut queue.tasks),
+            mem::take(&mut queue.woken),
+            mem::take(&mut queue.released),
+        );
+
+        mem::drop(queue);
+        mem::drop(taken);
+    }
+}
+
 impl Spawner {
+    /// Queues `t` on the worker. Once the worker is shutting down, `t` is dropped instead.
     pub(crate) fn spawn(&self, t: Task) {
-        let _ = self.sender.borrow_mut().push_back(t);
+        if !self.closed.get() {
+            self.sender.borrow_mut().push_back(t);
+        }
+    }
+
+    fn close(&self) {
+        self.closed.set(true);
+
+        let queued = mem::take(&mut *self.sender.borrow_mut());
+        mem::drop(queued);
     }
 }
@@ -1,49 +1,259 @@
-use bit_set::BitSet;
-use std::collections::LinkedList;
+use crate::sys::Task;
+use crate::task::Priority;
+use std::collections::VecDeque;
 
+/// How many tasks each run queue has room for before it first grows.
+const QUEUE_CAPACITY: usize = 4096;
+
+/// How many times in a row the LIFO slot may be taken over the queue, so a pair of tasks waking
+/// each other can't starve everything else.
+const MAX_LIFO_POLLS: u8 = 3;
+
+/// How many polls each [`Priority`], from high to low, gets per round while all of them have
+/// tasks ready.
+const WEIGHTS: [u8; 3] = [8, 4, 1];
+
+/// The run queues, one FIFO ring of tasks per [`Priority`], plus a LIFO slot for the most recently
+/// woken task.
+///
+/// A task woken by the one being polled (e.g. the receiving end of a channel) is likely to touch
+/// the same data, so it runs next while that is still in cache. A task waking itself, as
+/// `yield_now` and the coop budget do, goes to the back of its queue instead.
+///
+/// Priorities are served by weighted round robin: within a round, the highest priority with tasks
+/// ready and polls left to spend goes next, and a new round starts once every priority with tasks
+/// ready has spent its [`WEIGHTS`]. Lower priorities are slowed down but never starved.
 pub(crate) struct Scheduler {
-    queue: LinkedList<usize>,
-    in_queue: BitSet,
+    queues: [VecDeque<Task>; 3],
+    credits: [u8; 3],
+    lifo: Option<Task>,
+    lifo_polls: u8,
+    current: Option<usize>,
 }
 
 impl Scheduler {
     pub(crate) fn new() -> Self {
-        let queue = LinkedList::new();
-        let in_queue = BitSet::new();
+        let queues = [(); 3].map(|_| VecDeque::with_capacity(QUEUE_CAPACITY));
 
-        Self { queue, in_queue }
+        Self {
+            queues,
+            credits: WEIGHTS,
+            lifo: None,
+            lifo_polls: 0,
+            current: None,
+        }
     }
 
     #[inline]
-    pub(crate) fn spawn(&mut self, key: usize) -> usize {
-        assert!(
-            self.in_queue.insert(key),
-            "Attempted to double-spawn task {key}"
-        );
-
-        self.queue.push_back(key);
-        key
+    pub(crate) fn spawn(&mut self, task: Task) {
+        if task.set_scheduled() {
+            self.push_back(task);
+        }
     }
 
     #[inline]
-    pub(crate) fn wake(&mut self, key: usize) {
-        if !self.in_queue.contains(key) {
-            self.queue.push_back(key);
-            self.in_queue.insert(key);
+    pub(crate) fn wake(&mut self, task: &Task) {
+        if !task.set_scheduled() {
+            return;
         }
+
+        let task = task.clone();
+
+        if self.current == Some(task.id()) {
+            self.push_back(task);
+        } else if let Some(prev) = self.lifo.replace(task) {
+            self.push_back(prev);
+        }
+    }
+
+    #[inline]
+    pub(crate) fn fetch_next_task_for_tick(&mut self) -> Option<Task> {
+        let class = self.next_class()?;
+
+        self.credits[class] -= 1;
+
+        let task = match self.lifo.take() {
+            Some(task)
+                if class_of(&task) == class
+                    && (self.lifo_polls < MAX_LIFO_POLLS || self.queues[class].is_empty()) =>
+            {
+                self.lifo_polls = self.lifo_polls.saturating_add(1);
+
+                task
+            }
+            lifo => {
+                self.lifo = lifo;
+                self.lifo_polls = 0;
+                self.queues[class].pop_front()?
+            }
+        };
+
+        task.unset_scheduled();
+
+        self.current = Some(task.id());
+
+        Some(task)
     }
 
+    /// Marks the task returned by the last fetch as done being polled.
     #[inline]
-    pub(crate) fn fetch_next_task_for_tick(&mut self) -> Option<usize> {
-        if let Some(key) = self.queue.pop_front() {
-            assert!(
-                self.in_queue.remove(key),
-                "Internal error: in_queue set did not contain removed key {key}",
-            );
-
-            Some(key)
-        } else {
-            None
+    pub(crate) fn finish_tick(&mut self) {
+        self.current = None;
+    }
+
+    fn push_back(&mut self, task: Task) {
+        self.queues[class_of(&task)].push_back(task);
+    }
+
+    fn next_class(&mut self) -> Option<usize> {
+        for _ in 0..2 {
+            let ready = |class: usize| {
+                !self.queues[class].is_empty()
+                    || self.lifo.as_ref().is_some_and(|t| class_of(t) == class)
+            };
+
+            if let Some(class) = (0..WEIGHTS.len()).find(|&c| self.credits[c] > 0 && ready(c)) {
+                return Some(class);
+            }
+
+            self.credits = WEIGHTS;
+        }
+
+        None
+    }
+}
+
+fn class_of(task: &Task) -> usize {
+    match task.priority() {
+        Priority::High => 0,
+        Priority::Normal => 1,
+        Priority::Low => 2,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Harness {
+        scheduler: Scheduler,
+        tasks: Vec<Task>,
+    }
+
+    impl Harness {
+        fn new(n: usize) -> Self {
+            let tasks = (0..n).map(|_| Task::new(async {}).0).collect();
+
+            Self {
+                scheduler: Scheduler::new(),
+                tasks,
+            }
+        }
+
+        fn with_priorities(priorities: &[Priority]) -> Self {
+            let harness = Self::new(priorities.len());
+
+            for (task, &priority) in harness.tasks.iter().zip(priorities) {
+                task.set_priority(priority);
+            }
+
+            harness
+        }
+
+        fn spawn(&mut self, i: usize) {
+            self.scheduler.spawn(self.tasks[i].clone());
+        }
+
+        fn wake(&mut self, i: usize) {
+            self.scheduler.wake(&self.tasks[i]);
+        }
+
+        fn next(&mut self) -> Option<usize> {
+            let task = self.scheduler.fetch_next_task_for_tick()?;
+
+            self.tasks.iter().position(|t| t.id() == task.id())
+        }
+    }
+
+    #[test]
+    fn test_lifo_slot() {
+        let mut harness = Harness::new(6);
+
+        for i in 0..3 {
+            harness.spawn(i);
+        }
+
+        assert_eq!(Some(0), harness.next());
+
+        // woken by task 0, so it runs ahead of the queue and displaces the earlier wake
+        harness.wake(5);
+        harness.wake(4);
+        harness.wake(4);
+        // task 0 waking itself goes to the back
+        harness.wake(0);
+        harness.scheduler.finish_tick();
+
+        assert_eq!(Some(4), harness.next());
+        harness.scheduler.finish_tick();
+
+        let order: Vec<_> = std::iter::from_fn(|| harness.next()).collect();
+        assert_eq!(vec![1, 2, 5, 0], order);
+    }
+
+    #[test]
+    fn test_lifo_starvation() {
+        let mut harness = Harness::new(3);
+
+        for i in 0..3 {
+            harness.spawn(i);
+        }
+
+        let mut order = Vec::new();
+
+        // 0 and 1 keep waking each other, which must not starve 2
+        for _ in 0..10 {
+            let i = harness.next().unwrap();
+            order.push(i);
+
+            if i < 2 {
+                harness.wake(1 - i);
+            }
+
+            harness.scheduler.finish_tick();
+        }
+
+        assert!(order.contains(&2), "{order:?}");
+    }
+
+    #[test]
+    fn test_priority_weights() {
+        let mut harness =
+            Harness::with_priorities(&[Priority::Low, Priority::Normal, Priority::High]);
+
+        for i in 0..3 {
+            harness.spawn(i);
+        }
+
+        let mut polls = [0; 3];
+
+        // every task is ready again right after being polled
+        for _ in 0..13 * 10 {
+            let i = harness.next().unwrap();
+            polls[i] += 1;
+
+            harness.scheduler.finish_tick();
+            harness.spawn(i);
+        }
+
+        assert_eq!([10, 40, 80], polls);
+
+        // with nothing else ready, a low priority task runs back to back
+        let mut harness = Harness::with_priorities(&[Priority::Low]);
+
+        for _ in 0..WEIGHTS[2] as usize + 2 {
+            harness.spawn(0);
+            assert_eq!(Some(0), harness.next());
+            harness.scheduler.finish_tick();
         }
     }
 }
@@ -1,40 +1,463 @@
-use crate::task::JoinHandle;
-use futures::pin_mut;
+use crate::sys::trace::TaskSpan;
+use crate::sys::{with_worker_context, Remote};
+use crate::task::{JoinHandle, Priority};
+use std::cell::{Cell, UnsafeCell};
 use std::future::Future;
+use std::mem::{self, ManuallyDrop};
 use std::pin::Pin;
-use std::task::Context;
-use tokio::sync::oneshot;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll, Waker};
+use std::thread;
 
+/// The task is in the run queue.
+const SCHEDULED: u8 = 1 << 0;
+
+/// The future has finished or was dropped at shutdown; it will not be polled again.
+const COMPLETE: u8 = 1 << 1;
+
+/// The [`JoinHandle`] is still around to take the output.
+const JOIN_INTEREST: u8 = 1 << 2;
+
+/// The type-erased start of every task cell, which [`Task`]s and wakers point at.
+pub(crate) struct Header {
+    state: Cell<u8>,
+    priority: Cell<Priority>,
+    // wakers may be dropped on other threads, so this one count is atomic
+    refs: AtomicUsize,
+    // the worker that took the task in, which wakes and frees from other threads go back to
+    owner: OnceLock<Arc<Remote>>,
+    // set while a wake from another thread is queued on the owner
+    woken_remotely: AtomicBool,
+    vtable: &'static Vtable,
+    // links in the worker's list of live tasks
+    prev: Cell<Option<NonNull<Header>>>,
+    next: Cell<Option<NonNull<Header>>>,
+    join_waker: UnsafeCell<Option<Waker>>,
+    span: TaskSpan,
+}
+
+struct Vtable {
+    poll: unsafe fn(NonNull<Header>, &mut Context<'_>) -> bool,
+    read_output: unsafe fn(NonNull<Header>, *mut ()),
+    drop_stage: unsafe fn(NonNull<Header>),
+    dealloc: unsafe fn(NonNull<Header>),
+}
+
+/// A task's single allocation: the header, then the future or, once it finishes, its output.
+#[repr(C)]
+struct TaskCell<F: Future> {
+    header: Header,
+    stage: UnsafeCell<Stage<F>>,
+}
+
+enum Stage<F: Future> {
+    Running(F),
+    Finished(F::Output),
+    Consumed,
+}
+
+/// A counted reference to a task cell. The worker's task list, the run queue, the
+/// [`JoinHandle`] and every waker each hold one.
 pub(crate) struct Task {
-    inner: Pin<Box<dyn Future<Output = ()>>>,
+    ptr: NonNull<Header>,
 }
 
 impl Task {
-    pub(crate) fn new<F: 'static, T: 'static>(fut: F) -> (Task, JoinHandle<T>)
+    pub(crate) fn new<F, T>(fut: F) -> (Task, JoinHandle<T>)
     where
-        F: Future<Output = T>,
-        T: Send + Sync,
+        F: Future<Output = T> + 'static,
+        T: 'static,
     {
-        let (tx, rx) = oneshot::channel();
-
-        let inner = Box::pin(async move {
-            let out = fut.await;
+        Self::with_result(async move { Ok(fut.await) })
+    }
 
-            let _ = tx.send(out);
+    /// Like [`new`](Self::new), for a future that already carries a panic to resume in whichever
+    /// task joins it.
+    pub(crate) fn with_result<F, T>(fut: F) -> (Task, JoinHandle<T>)
+    where
+        F: Future<Output = thread::Result<T>> + 'static,
+        T: 'static,
+    {
+        let cell = Box::new(TaskCell {
+            header: Header {
+                state: Cell::new(JOIN_INTEREST),
+                priority: Cell::new(Priority::Normal),
+                refs: AtomicUsize::new(2),
+                owner: OnceLock::new(),
+                woken_remotely: AtomicBool::new(false),
+                vtable: vtable::<F>(),
+                prev: Cell::new(None),
+                next: Cell::new(None),
+                join_waker: UnsafeCell::new(None),
+                span: TaskSpan::spawned(),
+            },
+            stage: UnsafeCell::new(Stage::Running(fut)),
         });
 
-        let task = Task { inner };
+        let ptr = NonNull::from(Box::leak(cell)).cast::<Header>();
+
+        (Task { ptr }, JoinHandle::new(Task { ptr }))
+    }
+
+    /// Takes over the reference behind a pointer from [`into_raw`](Self::into_raw).
+    pub(crate) unsafe fn from_raw(ptr: *const ()) -> Task {
+        Task {
+            ptr: NonNull::new_unchecked(ptr as *mut Header),
+        }
+    }
+
+    pub(crate) fn into_raw(self) -> *const () {
+        ManuallyDrop::new(self).ptr.as_ptr() as *const ()
+    }
+
+    /// Identifies the task for as long as it is alive.
+    pub(crate) fn id(&self) -> usize {
+        self.ptr.as_ptr() as usize
+    }
+
+    fn header(&self) -> &Header {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    /// Ties the task to the worker that polls it. Called as the worker takes it in, before any
+    /// waker for it exists.
+    pub(crate) fn bind(&self, owner: Arc<Remote>) {
+        let _ = self.header().owner.set(owner);
+    }
 
-        let handle = JoinHandle::new(rx);
+    pub(crate) fn owner(&self) -> &Arc<Remote> {
+        self.header()
+            .owner
+            .get()
+            .expect("task was woken before a worker took it in")
+    }
+
+    /// Marks a wake from another thread as queued, returning false if one already was.
+    pub(crate) fn set_woken_remotely(&self) -> bool {
+        !self.header().woken_remotely.swap(true, Ordering::AcqRel)
+    }
+
+    pub(crate) fn unset_woken_remotely(&self) {
+        self.header().woken_remotely.store(false, Ordering::Release);
+    }
 
-        (task, handle)
+    pub(crate) fn priority(&self) -> Priority {
+        self.header().priority.get()
     }
 
-    pub(crate) fn poll_task(&mut self, cx: &mut Context<'_>) -> bool {
-        let pinned = self.inner.as_mut();
+    /// Must be set before the task is first scheduled.
+    pub(crate) fn set_priority(&self, priority: Priority) {
+        self.header().priority.set(priority);
+    }
+
+    /// Marks the task as queued, returning false if it already was or has completed.
+    pub(crate) fn set_scheduled(&self) -> bool {
+        let state = self.header().state.get();
+
+        if state & (SCHEDULED | COMPLETE) != 0 {
+            return false;
+        }
+
+        self.header().state.set(state | SCHEDULED);
+
+        true
+    }
+
+    pub(crate) fn unset_scheduled(&self) {
+        let state = &self.header().state;
+
+        state.set(state.get() & !SCHEDULED);
+    }
+
+    pub(crate) fn is_complete(&self) -> bool {
+        self.header().state.get() & COMPLETE != 0
+    }
+
+    /// Polls the future, returning true once it has finished and the output is stored.
+    pub(crate) fn poll(&self, cx: &mut Context<'_>) -> bool {
+        let done = {
+            let _polling = self.header().span.poll();
+
+            unsafe { (self.header().vtable.poll)(self.ptr, cx) }
+        };
+
+        if done {
+            self.header().span.completed();
+            self.complete();
+        }
+
+        done
+    }
+
+    /// Drops the future without finishing it. A task joining this one panics.
+    pub(crate) fn shutdown(&self) {
+        self.header().span.cancelled();
+
+        unsafe { (self.header().vtable.drop_stage)(self.ptr) };
+
+        self.complete();
+    }
+
+    fn complete(&self) {
+        let header = self.header();
+
+        header.state.set(header.state.get() | COMPLETE);
+
+        if header.state.get() & JOIN_INTEREST == 0 {
+            unsafe { (header.vtable.drop_stage)(self.ptr) };
+
+            return;
+        }
+
+        if let Some(waker) = unsafe { (*header.join_waker.get()).take() } {
+            waker.wake();
+        }
+    }
+
+    /// Takes the output for the [`JoinHandle`], or registers `cx` to be woken once it is there.
+    ///
+    /// # Safety
+    /// `T` must be the output type the task was created with.
+    pub(crate) unsafe fn poll_join<T>(&self, cx: &mut Context<'_>) -> Poll<thread::Result<T>> {
+        let header = self.header();
+
+        if header.state.get() & COMPLETE == 0 {
+            let join_waker = &mut *header.join_waker.get();
+
+            if !matches!(join_waker, Some(waker) if waker.will_wake(cx.waker())) {
+                *join_waker = Some(cx.waker().clone());
+            }
+
+            return Poll::Pending;
+        }
+
+        let mut out = Poll::Pending;
+
+        (header.vtable.read_output)(self.ptr, &mut out as *mut _ as *mut ());
+
+        out
+    }
+
+    /// Called as the [`JoinHandle`] is dropped, so the output is dropped as soon as it is ready.
+    pub(crate) fn drop_join_interest(&self) {
+        let header = self.header();
+        let state = header.state.get();
+
+        header.state.set(state & !JOIN_INTEREST);
+
+        let waker = unsafe { (*header.join_waker.get()).take() };
+        mem::drop(waker);
+
+        if state & COMPLETE != 0 {
+            unsafe { (header.vtable.drop_stage)(self.ptr) };
+        }
+    }
+}
+
+impl Clone for Task {
+    fn clone(&self) -> Self {
+        self.header().refs.fetch_add(1, Ordering::Relaxed);
+
+        Task { ptr: self.ptr }
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        if self.header().refs.fetch_sub(1, Ordering::AcqRel) != 1 {
+            return;
+        }
+
+        // the last waker may go on another thread, which hands the cell back to be freed. The
+        // owner is cloned, since the cell holding it may be gone by the time `release` returns
+        match self.header().owner.get().cloned() {
+            Some(owner) if with_worker_context(&owner, |_| ()).is_none() => {
+                owner.release(Released(self.ptr));
+            }
+            _ => unsafe { (self.header().vtable.dealloc)(self.ptr) },
+        }
+    }
+}
+
+/// A task reference on its way back to the owner's thread, after a wake on another one.
+pub(crate) struct Woken(Task);
+
+/// A task cell whose last reference was dropped on another thread, freed once it is dropped on
+/// the owner's.
+pub(crate) struct Released(NonNull<Header>);
+
+// Safety: the thread sending these only moves the pointer along, and the task's non-atomic state
+// is only touched back on the owner's thread. A cell is only freed elsewhere once the owner has
+// shut down, and since the worker holds every task that has yet to finish, its future and output
+// are gone by then
+unsafe impl Send for Woken {}
+unsafe impl Send for Released {}
+
+impl Woken {
+    pub(crate) fn new(task: Task) -> Self {
+        Self(task)
+    }
+
+    pub(crate) fn into_task(self) -> Task {
+        self.0
+    }
+}
+
+impl Drop for Released {
+    fn drop(&mut self) {
+        unsafe { (self.0.as_ref().vtable.dealloc)(self.0) };
+    }
+}
+
+fn vtable<F: Future + 'static>() -> &'static Vtable {
+    &Vtable {
+        poll: poll::<F>,
+        read_output: read_output::<F>,
+        drop_stage: drop_stage::<F>,
+        dealloc: dealloc::<F>,
+    }
+}
+
+unsafe fn stage<'a, F: Future>(ptr: NonNull<Header>) -> &'a mut Stage<F> {
+    &mut *ptr.cast::<TaskCell<F>>().as_ref().stage.get()
+}
+
+unsafe fn poll<F: Future>(ptr: NonNull<Header>, cx: &mut Context<'_>) -> bool {
+    let stage = stage::<F>(ptr);
+
+    let fut = match stage {
+        Stage::Running(fut) => Pin::new_unchecked(fut),
+        _ => return false,
+    };
+
+    match fut.poll(cx) {
+        Poll::Ready(out) => {
+            *stage = Stage::Finished(out);
+
+            true
+        }
+        Poll::Pending => false,
+    }
+}
+
+unsafe fn read_output<F: Future>(ptr: NonNull<Header>, dst: *mut ()) {
+    match mem::replace(stage::<F>(ptr), Stage::Consumed) {
+        Stage::Finished(out) => *(dst as *mut Poll<F::Output>) = Poll::Ready(out),
+        _ => panic!("task was cancelled or its output already taken"),
+    }
+}
+
+unsafe fn drop_stage<F: Future>(ptr: NonNull<Header>) {
+    // moved out first, so the stage is consistent while the destructor runs
+    let stage = mem::replace(stage::<F>(ptr), Stage::Consumed);
+    mem::drop(stage);
+}
+
+unsafe fn dealloc<F: Future>(ptr: NonNull<Header>) {
+    mem::drop(Box::from_raw(ptr.cast::<TaskCell<F>>().as_ptr()));
+}
+
+/// The worker's tasks that have not completed yet, linked through their headers. Tasks still in
+/// the list when it is dropped are leaked.
+#[derive(Default)]
+pub(crate) struct TaskList {
+    head: Option<NonNull<Header>>,
+    len: usize,
+}
+
+impl TaskList {
+    pub(crate) fn push(&mut self, task: Task) {
+        let ptr = ManuallyDrop::new(task).ptr;
+
+        unsafe {
+            ptr.as_ref().next.set(self.head);
+
+            if let Some(head) = self.head {
+                head.as_ref().prev.set(Some(ptr));
+            }
+        }
+
+        self.head = Some(ptr);
+        self.len += 1;
+    }
+
+    /// Unlinks `task`, which must be in this list, handing back the list's reference.
+    pub(crate) fn remove(&mut self, task: &Task) -> Task {
+        let header = task.header();
+
+        let (prev, next) = (header.prev.take(), header.next.take());
+
+        match prev {
+            Some(prev) => unsafe { prev.as_ref().next.set(next) },
+            None => self.head = next,
+        }
+
+        if let Some(next) = next {
+            unsafe { next.as_ref().prev.set(prev) };
+        }
+
+        self.len -= 1;
+
+        Task { ptr: task.ptr }
+    }
+
+    pub(crate) fn pop_front(&mut self) -> Option<Task> {
+        let task = ManuallyDrop::new(Task { ptr: self.head? });
+
+        Some(self.remove(&task))
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_task_cell() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let dropped = Rc::new(());
+        let witness = dropped.clone();
+
+        let (task, mut handle) = Task::new(async move {
+            let _witness = witness;
+
+            7
+        });
+
+        let mut list = TaskList::default();
+        list.push(task.clone());
+
+        assert!(Pin::new(&mut handle).poll(&mut cx).is_pending());
+
+        assert!(task.poll(&mut cx));
+        assert!(task.is_complete());
+        assert!(!task.set_scheduled());
+
+        // the future is gone as soon as it finishes, and the output lives on in the same cell
+        assert_eq!(1, Rc::strong_count(&dropped));
+        assert_eq!(Poll::Ready(7), Pin::new(&mut handle).poll(&mut cx));
+
+        mem::drop(list.remove(&task));
+        assert!(list.is_empty());
+
+        // dropped before completing, without a handle left to join it
+        let (task, handle) = Task::new(std::future::pending::<()>());
 
-        pin_mut!(pinned);
+        mem::drop(handle);
+        list.push(task.clone());
+        list.pop_front().unwrap().shutdown();
 
-        pinned.poll(cx).is_ready()
+        assert!(task.is_complete());
+        assert_eq!(1, task.header().refs.load(Ordering::Relaxed));
     }
 }
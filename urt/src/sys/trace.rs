@@ -0,0 +1,190 @@
+//! Instrumentation for the optional `tracing` feature. Without it, everything here compiles to
+//! nothing.
+
+use io_uring::{cqueue, squeue};
+
+#[cfg(feature = "tracing")]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The span of one task, open from spawn until the task is freed. Each poll runs in a `poll`
+/// span inside it.
+pub(crate) struct TaskSpan {
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+/// Exits the `poll` span when dropped.
+pub(crate) struct Polling {
+    #[cfg(feature = "tracing")]
+    _entered: tracing::span::EnteredSpan,
+}
+
+impl TaskSpan {
+    /// Opens the span of a newly spawned task, as a child of whatever span is current.
+    #[inline]
+    pub(crate) fn spawned() -> Self {
+        #[cfg(feature = "tracing")]
+        {
+            // task keys are addresses, which get reused, so spans get ids of their own
+            static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+            let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+            let span = tracing::trace_span!("task", task.id = id);
+
+            tracing::trace!(parent: &span, "spawned");
+
+            Self { span }
+        }
+
+        #[cfg(not(feature = "tracing"))]
+        Self {}
+    }
+
+    #[inline]
+    pub(crate) fn poll(&self) -> Polling {
+        #[cfg(feature = "tracing")]
+        {
+            let span = tracing::trace_span!(parent: &self.span, "poll");
+
+            Polling {
+                _entered: span.entered(),
+            }
+        }
+
+        #[cfg(not(feature = "tracing"))]
+        Polling {}
+    }
+
+    #[inline]
+    pub(crate) fn completed(&self) {
+        #[cfg(feature = "tracing")]
+        tracing::trace!(parent: &self.span, "completed");
+    }
+
+    #[inline]
+    pub(crate) fn cancelled(&self) {
+        #[cfg(feature = "tracing")]
+        tracing::trace!(parent: &self.span, "cancelled");
+    }
+}
+
+#[inline]
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn sqe(entry: &squeue::Entry, user_data: u64) {
+    // the entry's Debug output is the only way to get at its opcode
+    #[cfg(feature = "tracing")]
+    tracing::trace!(user_data, sqe = ?entry, "sqe pushed");
+}
+
+#[inline]
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn cqe(entry: &cqueue::Entry) {
+    #[cfg(feature = "tracing")]
+    tracing::trace!(
+        user_data = entry.user_data(),
+        result = entry.result(),
+        flags = entry.flags(),
+        "cqe reaped"
+    );
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use crate::rt::Runtime;
+    use std::fmt;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    /// Records the names of spans and the messages of events, noting which events carry a
+    /// `user_data`.
+    #[derive(Clone, Default)]
+    struct Recorder {
+        seen: Arc<Mutex<Vec<String>>>,
+        next_id: Arc<AtomicU64>,
+    }
+
+    #[derive(Default)]
+    struct Message(String);
+
+    impl Visit for Message {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            if field.name() == "message" {
+                self.0 = format!("{value:?}");
+            }
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            self.seen
+                .lock()
+                .unwrap()
+                .push(span.metadata().name().to_string());
+
+            Id::from_u64(self.next_id.fetch_add(1, Ordering::Relaxed) + 1)
+        }
+
+        fn record(&self, _: &Id, _: &Record<'_>) {}
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut message = Message::default();
+            event.record(&mut message);
+
+            let mut seen = self.seen.lock().unwrap();
+
+            if event.metadata().fields().field("user_data").is_some() {
+                seen.push(format!("{} with user_data", message.0));
+            }
+
+            seen.push(message.0);
+        }
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    #[test]
+    fn test_tracing() {
+        let recorder = Recorder::default();
+
+        tracing::subscriber::with_default(recorder.clone(), || {
+            let mut runtime = Runtime::new(256).unwrap();
+
+            runtime.spawn(async {
+                crate::time::sleep(Duration::from_millis(1)).await.unwrap();
+            });
+
+            runtime.run().unwrap();
+        });
+
+        let seen = recorder.seen.lock().unwrap();
+
+        for expected in [
+            "task",
+            "spawned",
+            "poll",
+            "sqe pushed",
+            "sqe pushed with user_data",
+            "cqe reaped",
+            "cqe reaped with user_data",
+            "completed",
+        ] {
+            assert!(
+                seen.iter().any(|s| s == expected),
+                "{expected} not in {seen:?}"
+            );
+        }
+    }
+}
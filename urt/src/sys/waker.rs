@@ -1,26 +1,64 @@
-use crate::sys::CONTEXT;
+use crate::sys::{with_worker_context, Task};
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
 use std::task::{RawWaker, RawWakerVTable, Waker};
 
-const VTABLE: RawWakerVTable = RawWakerVTable::new(make_raw_waker, wake, wake, drop_waker);
+const VTABLE: RawWakerVTable = RawWakerVTable::new(clone_waker, wake, wake_by_ref, drop_waker);
 
-pub(crate) fn make_waker(key: usize) -> Waker {
-    let key = key as *const ();
-    unsafe { Waker::from_raw(make_raw_waker(key)) }
+/// A waker for `task` that borrows the caller's reference instead of taking one, for the
+/// duration of a poll.
+pub(crate) struct WakerRef<'a> {
+    waker: ManuallyDrop<Waker>,
+    _task: &'a Task,
 }
 
-const fn make_raw_waker(key: *const ()) -> RawWaker {
-    RawWaker::new(key, &VTABLE)
+pub(crate) fn waker_ref(task: &Task) -> WakerRef<'_> {
+    let ptr = task.id() as *const ();
+
+    WakerRef {
+        waker: ManuallyDrop::new(unsafe { Waker::from_raw(RawWaker::new(ptr, &VTABLE)) }),
+        _task: task,
+    }
+}
+
+impl Deref for WakerRef<'_> {
+    type Target = Waker;
+
+    fn deref(&self) -> &Waker {
+        &self.waker
+    }
+}
+
+unsafe fn clone_waker(ptr: *const ()) -> RawWaker {
+    let task = ManuallyDrop::new(Task::from_raw(ptr));
+
+    RawWaker::new(Task::clone(&task).into_raw(), &VTABLE)
 }
 
-fn wake(key: *const ()) {
-    let key = key as usize;
+unsafe fn wake(ptr: *const ()) {
+    let task = Task::from_raw(ptr);
 
-    CONTEXT.with(|x| {
-        let borrow = x.borrow();
-        let context = borrow.as_ref().unwrap();
+    schedule(&task);
+}
+
+unsafe fn wake_by_ref(ptr: *const ()) {
+    let task = ManuallyDrop::new(Task::from_raw(ptr));
+
+    schedule(&task);
+}
 
-        context.scheduler.borrow_mut().wake(key);
-    })
+unsafe fn drop_waker(ptr: *const ()) {
+    drop(Task::from_raw(ptr));
 }
 
-fn drop_waker(_: *const ()) {}
+/// Queues `task` on its worker: directly when woken on the worker's own thread, or through its
+/// remote queue from anywhere else, as the scheduler and the task's state are not thread-safe.
+fn schedule(task: &Task) {
+    let owner = task.owner();
+
+    let woken = with_worker_context(owner, |context| context.scheduler.borrow_mut().wake(task));
+
+    if woken.is_none() {
+        owner.wake(task.clone());
+    }
+}
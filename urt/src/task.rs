@@ -1,16 +1,113 @@
-use futures::pin_mut;
+use crate::sys::{self, Task};
+use futures::{future, ready};
+use std::cell::RefCell;
 use std::future::Future;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::{fmt, mem, panic, thread};
 use tokio::sync::oneshot;
 
+pub use crate::sys::spawn_blocking;
+
+/// Yields back to the scheduler, letting other ready tasks run before this one continues.
+pub async fn yield_now() {
+    let mut yielded = false;
+
+    future::poll_fn(|cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+
+        yielded = true;
+        cx.waker().wake_by_ref();
+
+        Poll::Pending
+    })
+    .await
+}
+
+/// How urgently a task is polled relative to the others on its worker.
+///
+/// While tasks of every priority are ready, a worker polls high priority tasks 8 times and normal
+/// ones 4 times for every poll of a low priority task, so background work slows down without
+/// stalling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+/// Configures a task before spawning it.
+///
+/// ```no_run
+/// use urt::task::{Builder, Priority};
+///
+/// # async fn compact() {}
+/// # async fn example() {
+/// let compaction = Builder::new().priority(Priority::Low).spawn(compact());
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Builder {
+    priority: Priority,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the task's priority, [`Priority::Normal`] by default. Tasks it spawns do not inherit
+    /// it.
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Spawns the task on the current runtime, like [`spawn`](crate::spawn).
+    pub fn spawn<T, F>(self, fut: F) -> JoinHandle<T>
+    where
+        F: Future<Output = T> + 'static,
+        T: Send + 'static + Sync,
+    {
+        let (task, handle) = Task::new(fut);
+
+        task.set_priority(self.priority);
+
+        sys::spawn_task(task);
+
+        handle
+    }
+}
+
+/// Awaits the output of a spawned task, which shares the task's allocation until it is taken.
+///
+/// A task spawned from another thread through a [`Handle`](crate::rt::Handle) hands its output
+/// over through a channel instead, so the handle can be awaited on that thread.
 pub struct JoinHandle<T> {
-    inner: oneshot::Receiver<T>,
+    inner: JoinInner<T>,
+}
+
+enum JoinInner<T> {
+    Local(Task, PhantomData<fn() -> T>),
+    Remote(oneshot::Receiver<thread::Result<T>>),
 }
 
 impl<T> JoinHandle<T> {
-    pub(crate) fn new(inner: oneshot::Receiver<T>) -> Self {
-        Self { inner }
+    pub(crate) fn new(task: Task) -> Self {
+        Self {
+            inner: JoinInner::Local(task, PhantomData),
+        }
+    }
+
+    pub(crate) fn remote(rx: oneshot::Receiver<thread::Result<T>>) -> Self {
+        Self {
+            inner: JoinInner::Remote(rx),
+        }
     }
 }
 
@@ -18,12 +115,475 @@ impl<T> Future for JoinHandle<T> {
     type Output = T;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let pinned = self.get_mut();
+        let out = match &mut self.get_mut().inner {
+            // the handle was created along with the task, for the same output type
+            JoinInner::Local(task, _) => ready!(unsafe { task.poll_join::<T>(cx) }),
+            JoinInner::Remote(rx) => match ready!(Pin::new(rx).poll(cx)) {
+                Ok(out) => out,
+                Err(_) => panic!("task was cancelled or its output already taken"),
+            },
+        };
+
+        // a panic on the blocking pool or in a remote task is carried over and resumed here
+        Poll::Ready(match out {
+            Ok(out) => out,
+            Err(payload) => panic::resume_unwind(payload),
+        })
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        if let JoinInner::Local(task, _) = &self.inner {
+            task.drop_join_interest();
+        }
+    }
+}
+
+/// Declares task-local values, each a [`LocalKey`] that a task sets for the duration of a future
+/// with [`LocalKey::scope`].
+///
+/// ```no_run
+/// urt::task_local! {
+///     static REQUEST_ID: u64;
+/// }
+///
+/// # async fn handle() {}
+/// # async fn example() {
+/// REQUEST_ID.scope(7, async {
+///     handle().await;
+///
+///     REQUEST_ID.with(|id| assert_eq!(7, *id));
+/// })
+/// .await;
+/// # }
+/// ```
+#[macro_export]
+macro_rules! task_local {
+    () => {};
+
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty; $($rest:tt)*) => {
+        $crate::task_local!($(#[$attr])* $vis static $name: $t);
+        $crate::task_local!($($rest)*);
+    };
+
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty) => {
+        $(#[$attr])*
+        $vis static $name: $crate::task::LocalKey<$t> = {
+            ::std::thread_local! {
+                static __KEY: ::std::cell::RefCell<::std::option::Option<$t>> =
+                    const { ::std::cell::RefCell::new(::std::option::Option::None) };
+            }
+
+            $crate::task::LocalKey { inner: __KEY }
+        };
+    };
+}
+
+/// A key for a task-local value, declared with [`task_local!`](crate::task_local).
+///
+/// The value lives in the future passed to [`scope`](Self::scope) and is only visible while that
+/// future is being polled, so it follows the task across awaits without leaking into other tasks
+/// polled on the same worker in between. Tasks spawned from within a scope do not inherit it.
+pub struct LocalKey<T: 'static> {
+    #[doc(hidden)]
+    pub inner: std::thread::LocalKey<RefCell<Option<T>>>,
+}
+
+impl<T: 'static> LocalKey<T> {
+    /// Sets the value to `value` while `fut` runs.
+    pub fn scope<F: Future>(&'static self, value: T, fut: F) -> TaskLocalFuture<T, F> {
+        TaskLocalFuture {
+            key: self,
+            slot: Some(value),
+            fut: ManuallyDrop::new(fut),
+        }
+    }
+
+    /// Calls `f` with the current value.
+    ///
+    /// # Panics
+    /// Panics outside of a [`scope`](Self::scope) of this key.
+    pub fn with<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        match self.try_with(f) {
+            Ok(res) => res,
+            Err(_) => panic!("task-local value accessed outside of its scope"),
+        }
+    }
+
+    /// Calls `f` with the current value, or returns an error outside of a
+    /// [`scope`](Self::scope) of this key.
+    pub fn try_with<F, R>(&'static self, f: F) -> Result<R, AccessError>
+    where
+        F: FnOnce(&T) -> R,
+    {
+        self.inner
+            .try_with(|cell| cell.borrow().as_ref().map(f))
+            .ok()
+            .flatten()
+            .ok_or(AccessError { _private: () })
+    }
+
+    /// Swaps `slot` with the value visible to the task.
+    fn swap(&'static self, slot: &mut Option<T>) {
+        self.inner
+            .with(|cell| mem::swap(slot, &mut *cell.borrow_mut()));
+    }
+
+    /// Like [`swap`](Self::swap), but returns false instead of panicking if the value is borrowed
+    /// or the thread's locals are being torn down.
+    fn try_swap(&'static self, slot: &mut Option<T>) -> bool {
+        self.inner
+            .try_with(|cell| {
+                cell.try_borrow_mut()
+                    .map(|mut value| mem::swap(slot, &mut *value))
+                    .is_ok()
+            })
+            .unwrap_or(false)
+    }
+}
+
+impl<T: 'static> fmt::Debug for LocalKey<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("LocalKey { .. }")
+    }
+}
+
+/// A future running with a task-local value set, returned by [`LocalKey::scope`].
+pub struct TaskLocalFuture<T: 'static, F> {
+    key: &'static LocalKey<T>,
+    slot: Option<T>,
+    // dropped by hand, with the value set
+    fut: ManuallyDrop<F>,
+}
+
+/// Swaps the outer value back in as it is dropped, even while unwinding.
+struct Restore<'a, T: 'static> {
+    key: &'static LocalKey<T>,
+    slot: &'a mut Option<T>,
+}
+
+impl<T: 'static> Drop for Restore<'_, T> {
+    fn drop(&mut self) {
+        self.key.swap(self.slot);
+    }
+}
+
+impl<T: 'static, F: Future> Future for TaskLocalFuture<T, F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // `fut` is never moved out of the pinned struct
+        let this = unsafe { self.get_unchecked_mut() };
+        let fut = unsafe { Pin::new_unchecked(&mut *this.fut) };
+
+        this.key.swap(&mut this.slot);
+
+        // puts the outer value back even if `fut` panics
+        let _restore = Restore {
+            key: this.key,
+            slot: &mut this.slot,
+        };
+
+        fut.poll(cx)
+    }
+}
+
+impl<T: 'static, F> Drop for TaskLocalFuture<T, F> {
+    fn drop(&mut self) {
+        // the future's destructors may read the value as well, which they can't if this runs
+        // inside a `with` of the key or as the thread exits
+        let _restore = self.key.try_swap(&mut self.slot).then(|| Restore {
+            key: self.key,
+            slot: &mut self.slot,
+        });
+
+        // dropped in place, so pinning holds
+        unsafe { ManuallyDrop::drop(&mut self.fut) };
+    }
+}
+
+impl<T: 'static, F> fmt::Debug for TaskLocalFuture<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("TaskLocalFuture { .. }")
+    }
+}
+
+/// Returned by [`LocalKey::try_with`] outside of a scope of the key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessError {
+    _private: (),
+}
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("task-local value not set")
+    }
+}
+
+impl std::error::Error for AccessError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rt::Runtime;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn test_yield_now() {
+        let mut runtime = Runtime::new(256).unwrap();
+
+        let order = Rc::new(RefCell::new(Vec::new()));
+
+        for id in 0..2 {
+            let order = order.clone();
+
+            runtime.spawn(async move {
+                for _ in 0..3 {
+                    order.borrow_mut().push(id);
+
+                    yield_now().await;
+                }
+            });
+        }
+
+        runtime.run().unwrap();
+
+        assert_eq!(vec![0, 1, 0, 1, 0, 1], *order.borrow());
+    }
+
+    #[test]
+    fn test_priority() {
+        let mut runtime = Runtime::new(256).unwrap();
+
+        let order = Rc::new(RefCell::new(Vec::new()));
+        let log = order.clone();
+
+        runtime.spawn(async move {
+            let spawn = |priority, name| {
+                let log = log.clone();
+
+                Builder::new().priority(priority).spawn(async move {
+                    for _ in 0..2 {
+                        log.borrow_mut().push(name);
+
+                        yield_now().await;
+                    }
+                })
+            };
+
+            // spawned first, but only runs once the high priority task is done
+            let low = spawn(Priority::Low, "low");
+            let high = spawn(Priority::High, "high");
+
+            low.await;
+            high.await;
+        });
+
+        runtime.run().unwrap();
+
+        assert_eq!(vec!["high", "high", "low", "low"], *order.borrow());
+    }
+
+    #[test]
+    fn test_coop_budget() {
+        use crate::net::UnixStream;
+        use std::cell::Cell;
+
+        let (mut rx, tx) = UnixStream::pair().unwrap();
+
+        // far more reads than the budget, every one of which is ready immediately
+        const LEN: usize = 4096;
+
+        let mut runtime = Runtime::new(256).unwrap();
+
+        let other_ran = Rc::new(Cell::new(false));
+        let ran = other_ran.clone();
+
+        runtime.spawn(async move {
+            let mut tx = tx;
+            tx.write(&[7; LEN]).await.unwrap();
+
+            let mut buf = [0; 1];
+            let mut read = 0;
+
+            while read < LEN {
+                read += rx.read(&mut buf).await.unwrap();
+            }
+
+            assert!(ran.get());
+        });
+
+        runtime.spawn(async move {
+            other_ran.set(true);
+        });
+
+        runtime.run().unwrap();
+    }
+
+    #[test]
+    fn test_spawn_blocking() {
+        let mut runtime = Runtime::builder().max_blocking_threads(2).build().unwrap();
+
+        runtime.spawn(async {
+            let ring_thread = thread::current().id();
+
+            let id = spawn_blocking(|| thread::current().id()).await;
+            assert_ne!(ring_thread, id);
+
+            // with that thread now idle, a burst still runs side by side on a second one
+            thread::sleep(Duration::from_millis(20));
+
+            let arrived = Arc::new((Mutex::new(0), Condvar::new()));
+
+            let handles: Vec<_> = (0..2)
+                .map(|_| {
+                    let arrived = arrived.clone();
+
+                    spawn_blocking(move || {
+                        let (count, condvar) = &*arrived;
+
+                        *count.lock().unwrap() += 1;
+                        condvar.notify_all();
+
+                        let (count, _) = condvar
+                            .wait_timeout_while(
+                                count.lock().unwrap(),
+                                Duration::from_secs(5),
+                                |n| *n < 2,
+                            )
+                            .unwrap();
+
+                        *count == 2
+                    })
+                })
+                .collect();
+
+            for handle in handles {
+                assert!(handle.await, "blocking jobs ran one after another");
+            }
+
+            // more jobs than threads still all complete, at most two at a time
+            let running = Arc::new(AtomicUsize::new(0));
+            let peak = Arc::new(AtomicUsize::new(0));
+
+            let handles: Vec<_> = (0..8)
+                .map(|i| {
+                    let running = running.clone();
+                    let peak = peak.clone();
+
+                    spawn_blocking(move || {
+                        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        peak.fetch_max(now, Ordering::SeqCst);
+
+                        thread::sleep(Duration::from_millis(10));
+
+                        running.fetch_sub(1, Ordering::SeqCst);
+
+                        i
+                    })
+                })
+                .collect();
+
+            for (i, handle) in handles.into_iter().enumerate() {
+                assert_eq!(i, handle.await);
+            }
+
+            assert!(peak.load(Ordering::SeqCst) <= 2);
+
+            // panics come back to the awaiting task
+            let handle = spawn_blocking(|| panic!("boom"));
+
+            let caught = crate::spawn(async move {
+                let res = futures::FutureExt::catch_unwind(panic::AssertUnwindSafe(handle)).await;
+
+                res.is_err()
+            });
+
+            assert!(caught.await);
+        });
+
+        runtime.run().unwrap();
+    }
+
+    #[test]
+    fn test_task_local() {
+        crate::task_local! {
+            static REQUEST_ID: u32;
+            static TENANT: String;
+        }
+
+        let mut runtime = Runtime::new(256).unwrap();
+
+        assert!(REQUEST_ID.try_with(|_| ()).is_err());
+
+        for id in 0..2 {
+            runtime.spawn(REQUEST_ID.scope(id, async move {
+                for _ in 0..3 {
+                    // the other task runs in between, with its own value
+                    yield_now().await;
+                    crate::time::sleep(Duration::from_millis(1)).await.unwrap();
+
+                    assert_eq!(id, REQUEST_ID.with(|id| *id));
+                }
+
+                let inner = TENANT.scope("acme".to_string(), async {
+                    REQUEST_ID
+                        .scope(id + 10, async {
+                            yield_now().await;
+
+                            TENANT.with(|tenant| format!("{tenant}/{}", REQUEST_ID.with(|id| *id)))
+                        })
+                        .await
+                });
+
+                assert_eq!(format!("acme/{}", id + 10), inner.await);
+                assert_eq!(id, REQUEST_ID.with(|id| *id));
+                assert!(TENANT.try_with(|_| ()).is_err());
+
+                let child = crate::spawn(async { REQUEST_ID.try_with(|id| *id).is_err() });
+                assert!(child.await);
+            }));
+        }
+
+        runtime.run().unwrap();
+
+        assert!(REQUEST_ID.try_with(|_| ()).is_err());
+
+        // a future dropped before it finishes still sees the value in its destructors
+        struct Guard(Rc<Cell<Option<u32>>>);
+
+        impl Drop for Guard {
+            fn drop(&mut self) {
+                self.0.set(Some(REQUEST_ID.with(|id| *id)));
+            }
+        }
+
+        let seen = Rc::new(Cell::new(None));
+        let guard = Guard(seen.clone());
+
+        let mut fut = Box::pin(REQUEST_ID.scope(3, async move {
+            let _guard = guard;
+
+            futures::future::pending::<()>().await;
+        }));
 
-        let inner = &mut pinned.inner;
+        let waker = futures::task::noop_waker();
+        assert!(fut
+            .as_mut()
+            .poll(&mut Context::from_waker(&waker))
+            .is_pending());
 
-        pin_mut!(inner);
+        mem::drop(fut);
 
-        inner.poll(cx).map(|x| x.unwrap())
+        assert_eq!(Some(3), seen.get());
+        assert!(REQUEST_ID.try_with(|_| ()).is_err());
     }
 }
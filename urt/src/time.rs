@@ -0,0 +1,26 @@
+use crate::io::cqe_result;
+use crate::submit_op;
+use io_uring::types::Timespec;
+use std::io;
+use std::time::Duration;
+
+/// Waits until `duration` has elapsed, using an io_uring timeout op.
+pub(crate) async fn sleep(duration: Duration) -> io::Result<()> {
+    let ts = Box::new(timespec(duration));
+
+    let entry = io_uring::opcode::Timeout::new(&*ts).build();
+
+    let (entry, _) = unsafe { submit_op(entry, ts) }?.await;
+
+    match cqe_result(&entry) {
+        // an expired timeout completes with ETIME
+        Err(e) if e.raw_os_error() == Some(libc::ETIME) => Ok(()),
+        res => res.map(|_| ()),
+    }
+}
+
+pub(crate) fn timespec(duration: Duration) -> Timespec {
+    Timespec::new()
+        .sec(duration.as_secs())
+        .nsec(duration.subsec_nanos())
+}
@@ -0,0 +1,46 @@
+//! Process-directed signals go to any thread that doesn't block them, so this runs as its own
+//! binary with the runtime on the main thread, rather than inside libtest and its threads.
+
+use std::io;
+use std::time::Duration;
+use urt::rt::Runtime;
+use urt::signal::{signal, SignalKind};
+
+fn main() {
+    let mut runtime = Runtime::new(256).unwrap();
+
+    runtime.spawn(async {
+        // started before any signal is registered, so blocking it on the ring thread doesn't
+        // cover this one
+        urt::task::spawn_blocking(|| ()).await;
+
+        let mut first = signal(SignalKind::user_defined1()).unwrap();
+        let mut second = signal(SignalKind::user_defined1()).unwrap();
+        let mut other = signal(SignalKind::user_defined2()).unwrap();
+
+        let waiter = urt::spawn(async move {
+            second.recv().await.unwrap();
+        });
+
+        // sent to the whole process, which may pick any thread not blocking it
+        assert_eq!(0, unsafe { libc::kill(libc::getpid(), libc::SIGUSR1) });
+
+        first.recv().await.unwrap();
+        waiter.await;
+
+        let err = signal(SignalKind::from_raw(libc::SIGKILL)).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+        // this time the read is already in flight when the signal arrives
+        let sender = urt::task::spawn_blocking(|| {
+            std::thread::sleep(Duration::from_millis(10));
+
+            assert_eq!(0, unsafe { libc::kill(libc::getpid(), libc::SIGUSR2) });
+        });
+
+        other.recv().await.unwrap();
+        sender.await;
+    });
+
+    runtime.run().unwrap();
+}